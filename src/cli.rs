@@ -0,0 +1,379 @@
+//! Command line interface of the binary. Running without a subcommand is the same as
+//! `process`, so `Transactor input.csv` keeps working.
+
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum, builder::TypedValueParser};
+use transactor::{
+    csv::{ColumnMap, MergeBy},
+    journal::FsyncPolicy,
+    ledger::{
+        Client, Timestamp, Tx,
+        snapshot::{SnapshotFilter, SnapshotOrder},
+        window::{DAY, DeadlineAction, DisputeWindow},
+    },
+    report::Format,
+};
+
+/// Apply a CSV file of transactions to client balances
+#[derive(Debug, Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub process: ProcessArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Apply the input and write the final balance of every client
+    Process(ProcessArgs),
+
+    /// Read the input and report every row which can't be parsed, without applying any
+    Validate(ValidateArgs),
+
+    /// Apply the input and write totals for each currency rather than each client
+    Summary(SummaryArgs),
+
+    /// Write a client's entries in the order they were applied
+    History(HistoryArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ProcessArgs {
+    /// CSV files of transactions, applied in order. `-` reads stdin. Optional when serving
+    pub input: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub report: ReportArgs,
+
+    /// Serve transactions over TCP on this address instead of reading a file. Nothing is
+    /// written when serving, so the options for files and reports can't be given with it
+    #[arg(long, value_name = "ADDR", conflicts_with_all = [
+        "input", "threads", "save_state", "rejects", "trial_balance", "output", "format",
+        "sort", "only",
+    ])]
+    pub serve: Option<String>,
+
+    /// Connections served at once, 64 by default. Any more are refused until one closes
+    #[arg(long, value_name = "COUNT", requires = "serve", conflicts_with = "input",
+        value_parser = clap::value_parser!(u16).range(1..).map(usize::from))]
+    pub max_connections: Option<usize>,
+
+    /// Seconds a connection may go without sending a line before it's closed, 300 by
+    /// default. 0 never closes an idle connection
+    #[arg(
+        long,
+        value_name = "SECS",
+        requires = "serve",
+        conflicts_with = "input"
+    )]
+    pub idle_timeout: Option<u64>,
+
+    /// Order of the balances. Amounts are sorted largest first, ties by client
+    #[arg(long, value_enum, default_value_t = SortArg::Client)]
+    pub sort: SortArg,
+
+    /// Only write the balances of frozen accounts or of accounts with funds held
+    #[arg(long, value_enum, value_name = "ACCOUNTS")]
+    pub only: Option<OnlyArg>,
+
+    #[command(flatten)]
+    pub ledger: LedgerArgs,
+}
+
+impl ProcessArgs {
+    /// The order of the balances the options describe
+    pub fn order(&self) -> SnapshotOrder {
+        match self.sort {
+            SortArg::Client => SnapshotOrder::Client,
+            SortArg::Total => SnapshotOrder::Total,
+            SortArg::Available => SnapshotOrder::Available,
+            SortArg::Held => SnapshotOrder::Held,
+        }
+    }
+
+    /// The balances the options keep, if they don't keep every one
+    pub fn filter(&self) -> Option<SnapshotFilter> {
+        self.only.map(|only| match only {
+            OnlyArg::Locked => SnapshotFilter::Locked,
+            OnlyArg::Held => SnapshotFilter::Held,
+        })
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ValidateArgs {
+    /// CSV files of transactions. `-` reads stdin
+    #[arg(required = true)]
+    pub input: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub report: ReportArgs,
+
+    /// Accept administrative transactions such as unlocks and manual adjustments
+    #[arg(long)]
+    pub allow_admin: bool,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct SummaryArgs {
+    /// CSV files of transactions. `-` reads stdin
+    #[arg(required = true)]
+    pub input: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub report: ReportArgs,
+
+    #[command(flatten)]
+    pub ledger: LedgerArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct HistoryArgs {
+    /// Client whose entries are written
+    pub client: Client,
+
+    /// CSV files of transactions, applied in order. `-` reads stdin. Optional when loading
+    /// a saved state or journal
+    pub input: Vec<PathBuf>,
+
+    /// Only write this transaction
+    #[arg(long)]
+    pub tx: Option<Tx>,
+
+    /// Skip this many entries
+    #[arg(long, default_value_t = 0)]
+    pub offset: usize,
+
+    /// Write at most this many entries
+    #[arg(long, default_value_t = usize::MAX, hide_default_value = true)]
+    pub limit: usize,
+
+    #[command(flatten)]
+    pub report: ReportArgs,
+
+    #[command(flatten)]
+    pub ledger: LedgerArgs,
+}
+
+/// How the inputs are laid out
+#[derive(Debug, Args)]
+pub struct LayoutArgs {
+    /// Character separating the fields of the inputs. `tab` for tab separated input
+    #[arg(long, value_name = "CHAR", default_value = ",", value_parser = parse_delimiter)]
+    pub delimiter: u8,
+
+    /// Read the inputs' columns under other names, such as txn_type=type,customer_id=client
+    #[arg(long, value_name = "THEIRS=OURS,...")]
+    pub column_map: Option<ColumnMap>,
+}
+
+/// A delimiter is a single ASCII character
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "tab" | "\\t" => Ok(b'\t'),
+        s if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        s => Err(format!("expected a single ASCII character, got {}", s)),
+    }
+}
+
+/// Parse a number of days into seconds, refusing counts too large to represent
+fn parse_days(s: &str) -> Result<Timestamp, String> {
+    let days: u64 = s.parse().map_err(|e| format!("{}", e))?;
+    days.checked_mul(DAY)
+        .ok_or_else(|| format!("expected at most {} days", Timestamp::MAX / DAY))
+}
+
+/// Where reports are written and how they're laid out
+#[derive(Debug, Args)]
+pub struct ReportArgs {
+    /// Where to write the report. Defaults to stdout
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// How every report is written, including the rejects report and trial balance
+    #[arg(long, value_enum, default_value_t = FormatArg::Csv)]
+    pub format: FormatArg,
+}
+
+impl ReportArgs {
+    /// The format the options describe
+    pub fn format(&self) -> Format {
+        match self.format {
+            FormatArg::Csv => Format::Csv,
+            FormatArg::Json => Format::Json,
+            FormatArg::Ndjson => Format::Ndjson,
+            FormatArg::Table => Format::Table,
+        }
+    }
+}
+
+/// How the ledger is configured and where its state comes from and goes to
+#[derive(Debug, Args)]
+pub struct LedgerArgs {
+    /// Write every row which wasn't applied to a report, laid out as --format says
+    #[arg(long, value_name = "PATH")]
+    pub rejects: Option<PathBuf>,
+
+    /// Restore the ledger saved by an earlier run before reading the input
+    #[arg(long, value_name = "PATH")]
+    pub load_state: Option<PathBuf>,
+
+    /// Save the ledger once the input has been applied
+    #[arg(long, value_name = "PATH")]
+    pub save_state: Option<PathBuf>,
+
+    /// Append every accepted transaction to a journal, replaying it on startup
+    #[arg(long, value_name = "PATH", conflicts_with = "threads")]
+    pub journal: Option<PathBuf>,
+
+    /// How often the journal is forced to disk: always, never or a record count
+    #[arg(long, value_name = "POLICY", default_value = "always")]
+    pub fsync: FsyncPolicy,
+
+    /// Keep entries in a file at this path rather than in memory
+    #[arg(long, value_name = "PATH")]
+    pub store: Option<PathBuf>,
+
+    /// Stop at the first row which can't be read rather than skipping it
+    #[arg(long)]
+    pub strict: bool,
+
+    #[command(flatten)]
+    pub layout: LayoutArgs,
+
+    /// Merge the inputs on a column rather than applying them one after another
+    #[arg(long, value_enum, value_name = "COLUMN")]
+    pub merge_by: Option<MergeArg>,
+
+    /// Split clients between this many worker threads
+    #[arg(long, value_name = "COUNT", default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..))]
+    pub threads: u16,
+
+    /// Accept administrative transactions such as unlocks and manual adjustments
+    #[arg(long)]
+    pub allow_admin: bool,
+
+    /// Which transactions may be disputed
+    #[arg(long, value_enum, default_value_t = PolicyArg::Standard)]
+    pub dispute_policy: PolicyArg,
+
+    /// Reject disputes raised more than this many days after their transaction
+    #[arg(long, value_name = "DAYS", value_parser = parse_days)]
+    pub dispute_window: Option<Timestamp>,
+
+    /// Settle disputes still open this many days after they were raised
+    #[arg(long, value_name = "DAYS", value_parser = parse_days)]
+    pub resolve_within: Option<Timestamp>,
+
+    /// How a dispute is settled once its deadline passes
+    #[arg(long, value_enum, default_value_t = DeadlineArg::Resolve)]
+    pub on_deadline: DeadlineArg,
+
+    /// CSV file of overdraft limits with a client,limit[,currency] header
+    #[arg(long, value_name = "PATH")]
+    pub credit_limits: Option<PathBuf>,
+
+    /// CSV file of fee rules with a type,min,max,fee[,currency] header
+    #[arg(long, value_name = "PATH")]
+    pub fees: Option<PathBuf>,
+
+    /// Keep double-entry books alongside the balances
+    #[arg(long)]
+    pub double_entry: bool,
+
+    /// Write the trial balance of the books, laid out as --format says. Implies
+    /// --double-entry
+    #[arg(long, value_name = "PATH")]
+    pub trial_balance: Option<PathBuf>,
+
+    /// Lift an account's freeze once its last charge back is represented
+    #[arg(long)]
+    pub unlock_on_represent: bool,
+}
+
+impl LedgerArgs {
+    /// The dispute window the options describe. Both windows were parsed from days into
+    /// seconds by [`parse_days`]
+    pub fn window(&self) -> DisputeWindow {
+        DisputeWindow {
+            dispute_within: self.dispute_window,
+            resolve_within: self.resolve_within,
+            on_deadline: match self.on_deadline {
+                DeadlineArg::Resolve => DeadlineAction::Resolve,
+                DeadlineArg::Chargeback => DeadlineAction::ChargeBack,
+            },
+        }
+    }
+
+    /// The column the inputs are merged on, if any
+    pub fn merge_by(&self) -> Option<MergeBy> {
+        self.merge_by.map(|column| match column {
+            MergeArg::Timestamp => MergeBy::Timestamp,
+            MergeArg::Sequence => MergeBy::Sequence,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PolicyArg {
+    /// Deposits, withdrawals and transfers may be disputed
+    Standard,
+    /// Only deposits may be disputed
+    DepositsOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DeadlineArg {
+    /// Release the hold back to the client
+    Resolve,
+    /// Charge the dispute back
+    Chargeback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MergeArg {
+    /// Merge on the timestamp column
+    Timestamp,
+    /// Merge on the sequence column
+    Sequence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FormatArg {
+    /// CSV with a header row
+    Csv,
+    /// A JSON array of objects
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Columns aligned for reading in a terminal
+    Table,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortArg {
+    /// By client, then currency
+    Client,
+    /// Largest total first
+    Total,
+    /// Largest available balance first
+    Available,
+    /// Largest held balance first
+    Held,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnlyArg {
+    /// Frozen accounts
+    Locked,
+    /// Accounts with funds held by an open dispute
+    Held,
+}
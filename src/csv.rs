@@ -1,15 +1,35 @@
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     fmt::Display,
-    io::{self, Write},
+    io::{self, Read, Write},
+    iter::Peekable,
+    rc::Rc,
+    str::FromStr,
+    sync::Arc,
 };
 
-use csv::WriterBuilder;
+use csv::{ReaderBuilder, StringRecord, StringRecordsIntoIter, Trim};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::ledger::{Transaction, balance::BalanceSnapshot};
+use crate::{
+    ledger::{
+        AdminAction, Client, Entry, Operator, Timestamp, Transaction, Tx, TxStatus,
+        amount::Amount,
+        balance::BalanceSnapshot,
+        books::TrialBalance,
+        currency::Currency,
+        fees::{FeeRule, FeeSchedule, FeeTrigger},
+        limits::CreditLimits,
+        summary::Summary,
+    },
+    reject::Rejection,
+    report::{self, Format, write_records},
+};
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Missing amount on a transaction {0}")]
     MissingAmount(String),
@@ -17,6 +37,30 @@ pub enum Error {
     #[error("Unknown transaction type: {0}")]
     UnknownTransactionType(String),
 
+    #[error("Empty record")]
+    EmptyRecord,
+
+    #[error("Missing operator on an administrative transaction {0}")]
+    MissingOperator(String),
+
+    #[error("Missing reason on an administrative transaction {0}")]
+    MissingReason(String),
+
+    #[error("Missing recipient on a transfer {0}")]
+    MissingRecipient(String),
+
+    #[error("Administrative transactions are not accepted: {0}")]
+    AdminNotAllowed(Tx),
+
+    #[error("Credit limit can't be negative for client {0}")]
+    NegativeCreditLimit(Client),
+
+    #[error("Fee can't be negative on rule {0}")]
+    NegativeFee(usize),
+
+    #[error("Invalid column mapping: {0}")]
+    InvalidColumnMap(String),
+
     #[error("I/O Error: {0}")]
     IOError(#[from] io::Error),
 
@@ -32,7 +76,24 @@ pub struct CsvTransaction {
     pub client: u16,
     pub tx: u32,
     // This field is not always present for all types
-    pub amount: Option<f64>,
+    pub amount: Option<Amount>,
+    // Optional column. Deposits and withdrawals without one are held in `XXX`
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    // Optional columns which are only read for administrative transactions
+    #[serde(default)]
+    pub operator: Option<Operator>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    // Optional column of seconds since the Unix epoch. Only used to limit disputes in time
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+    // Optional column holding the client a transfer is sent to
+    #[serde(default)]
+    pub to: Option<Client>,
+    // Optional column numbering the rows of several inputs so they can be merged
+    #[serde(default)]
+    pub sequence: Option<u64>,
 }
 
 impl TryInto<Transaction> for CsvTransaction {
@@ -45,24 +106,97 @@ impl TryInto<Transaction> for CsvTransaction {
             client,
             tx,
             amount,
+            currency,
+            operator,
+            reason,
+            to,
+            ..
         } = self;
 
-        match t.to_lowercase().as_str() {
+        let currency = currency.unwrap_or_default();
+        let t = t.to_lowercase();
+
+        let action = match t.as_str() {
+            "unlock" => Some(AdminAction::Unlock),
+            "freeze" => Some(AdminAction::Freeze),
+            "credit" | "debit" => {
+                let amount = amount
+                    .ok_or_else(|| Error::MissingAmount(format!("amount absent from {}", t)))?;
+                Some(if t == "credit" {
+                    AdminAction::Credit { amount, currency }
+                } else {
+                    AdminAction::Debit { amount, currency }
+                })
+            }
+            _ => None,
+        };
+
+        if let Some(action) = action {
+            let operator = operator
+                .ok_or_else(|| Error::MissingOperator(format!("operator absent from {}", t)))?;
+            let reason = reason
+                .filter(|r| !r.trim().is_empty())
+                .ok_or_else(|| Error::MissingReason(format!("reason absent from {}", t)))?;
+
+            return Ok(Transaction::Admin {
+                client,
+                tx,
+                action,
+                operator,
+                reason,
+            });
+        }
+
+        match t.as_str() {
             "deposit" => amount.map_or_else(
-                || Err(Error::MissingAmount(format!("amount absent from deposit"))),
-                |amount| Ok(Transaction::Deposit { client, tx, amount }),
+                || {
+                    Err(Error::MissingAmount(
+                        "amount absent from deposit".to_string(),
+                    ))
+                },
+                |amount| {
+                    Ok(Transaction::Deposit {
+                        client,
+                        tx,
+                        amount,
+                        currency,
+                    })
+                },
             ),
             "withdrawal" => amount.map_or_else(
                 || {
-                    Err(Error::MissingAmount(format!(
-                        "amount absent from withdrawal"
-                    )))
+                    Err(Error::MissingAmount(
+                        "amount absent from withdrawal".to_string(),
+                    ))
+                },
+                |amount| {
+                    Ok(Transaction::Withdrawal {
+                        client,
+                        tx,
+                        amount,
+                        currency,
+                    })
                 },
-                |amount| Ok(Transaction::Withdrawal { client, tx, amount }),
             ),
-            "dispute" => Ok(Transaction::Dispute { client, tx }),
-            "resolve" => Ok(Transaction::Resolve { client, tx }),
-            "chargeback" => Ok(Transaction::ChargeBack { client, tx }),
+            "transfer" => {
+                let amount = amount.ok_or_else(|| {
+                    Error::MissingAmount("amount absent from transfer".to_string())
+                })?;
+                let to = to.ok_or_else(|| {
+                    Error::MissingRecipient("recipient absent from transfer".to_string())
+                })?;
+                Ok(Transaction::Transfer {
+                    from: client,
+                    to,
+                    tx,
+                    amount,
+                    currency,
+                })
+            }
+            "dispute" => Ok(Transaction::Dispute { client, tx, amount }),
+            "resolve" => Ok(Transaction::Resolve { client, tx, amount }),
+            "chargeback" => Ok(Transaction::ChargeBack { client, tx, amount }),
+            "represent" => Ok(Transaction::Represent { client, tx }),
             _ => Err(Error::UnknownTransactionType(format!(
                 "Unknown type: {}",
                 t
@@ -78,29 +212,419 @@ impl Display for CsvTransaction {
             .field("client", &self.client)
             .field("tx", &self.tx);
 
-        self.amount.map(|amount| s.field("amount", &amount));
+        if let Some(amount) = &self.amount {
+            s.field("amount", amount);
+        }
+        if let Some(currency) = &self.currency {
+            s.field("currency", currency);
+        }
+        if let Some(operator) = &self.operator {
+            s.field("operator", operator);
+        }
+        if let Some(reason) = &self.reason {
+            s.field("reason", reason);
+        }
+        if let Some(timestamp) = &self.timestamp {
+            s.field("timestamp", timestamp);
+        }
+        if let Some(to) = &self.to {
+            s.field("to", to);
+        }
+        if let Some(sequence) = &self.sequence {
+            s.field("sequence", sequence);
+        }
         s.finish()
     }
 }
 
+/// Column order of a record which arrives without a header row. These are also every
+/// column a header row may name
+const BARE_HEADERS: [&str; 10] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "currency",
+    "operator",
+    "reason",
+    "timestamp",
+    "to",
+    "sequence",
+];
+
+/// Renames the columns of an input onto the ones a [`CsvTransaction`] is read from, so
+/// files with other headers can be read as they are. Written as `theirs=ours` pairs
+/// separated by commas, such as `txn_type=type,customer_id=client,txn_id=tx,value=amount`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ColumnMap(HashMap<String, String>);
+
+impl ColumnMap {
+    /// The column a header is read as. Headers are matched regardless of case
+    fn get<'a>(&'a self, header: &'a str) -> &'a str {
+        self.0.get(header).map_or(header, String::as_str)
+    }
+}
+
+impl FromStr for ColumnMap {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut columns = HashMap::new();
+        for pair in s.split(',').filter(|p| !p.trim().is_empty()) {
+            let (theirs, ours) = pair.split_once('=').ok_or_else(|| {
+                Error::InvalidColumnMap(format!("expected theirs=ours, got {}", pair))
+            })?;
+            let ours = ours.trim().to_lowercase();
+            if !BARE_HEADERS.contains(&ours.as_str()) {
+                Err(Error::InvalidColumnMap(format!("unknown column {}", ours)))?;
+            }
+            columns.insert(theirs.trim().to_lowercase(), ours);
+        }
+        Ok(ColumnMap(columns))
+    }
+}
+
+/// Parse a single record without a header row, such as `deposit,1,1,1.0`, along with its
+/// timestamp if it has one. Trailing optional columns may be left off entirely.
+pub fn parse_transaction(line: &str) -> Result<(Transaction, Option<Timestamp>), Error> {
+    let mut csv_reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(line.as_bytes());
+
+    let record = csv_reader.records().next().ok_or(Error::EmptyRecord)??;
+    let headers = StringRecord::from(BARE_HEADERS.to_vec());
+
+    let tx: CsvTransaction = record.deserialize(Some(&headers))?;
+    let timestamp = tx.timestamp;
+    Ok((tx.try_into()?, timestamp))
+}
+
+/// A transaction along with the position in the input it was read from
+#[derive(Debug, Clone)]
+pub struct Row {
+    /// Line of the input the record started on
+    pub line: u64,
+
+    /// The record as it appeared in the input
+    pub record: String,
+
+    pub transaction: Transaction,
+
+    /// When the transaction happened, if the input has a timestamp column
+    pub timestamp: Option<Timestamp>,
+
+    /// Position of the row across every input, if the input has a sequence column
+    pub sequence: Option<u64>,
+
+    /// The input the row was read from, if it was named
+    pub source: Option<Arc<str>>,
+}
+
+/// Bytes handed to the CSV parser which no record has claimed yet
+#[derive(Debug, Default)]
+struct Unclaimed {
+    /// Offset within the input of the first byte held
+    offset: u64,
+    bytes: Vec<u8>,
+}
+
+impl Unclaimed {
+    /// The input between two offsets, without the line endings around it. Everything
+    /// before `end` is let go of, as the parser never reads backwards
+    fn claim(&mut self, start: u64, end: u64) -> String {
+        let idx = |at: u64| (at.saturating_sub(self.offset) as usize).min(self.bytes.len());
+        let (start, end_idx) = (idx(start), idx(end));
+
+        let mut raw = &self.bytes[start.min(end_idx)..end_idx];
+        while let [b'\n' | b'\r', rest @ ..] | [rest @ .., b'\n' | b'\r'] = raw {
+            raw = rest;
+        }
+        let raw = String::from_utf8_lossy(raw).into_owned();
+
+        self.bytes.drain(..end_idx);
+        self.offset = self.offset.max(end);
+        raw
+    }
+}
+
+/// Passes input through to the CSV parser while keeping a copy of it, so a record can be
+/// reported exactly as it was written rather than as the parser split it into fields
+struct Recorder<R> {
+    inner: R,
+    unclaimed: Rc<RefCell<Unclaimed>>,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.unclaimed
+            .borrow_mut()
+            .bytes
+            .extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Reads [`Transaction`]s out of CSV input with a header row. Each item is either the
+/// transaction from the next row or a [`Rejection`] describing why it couldn't be read.
+pub struct TransactionReader<R> {
+    headers: StringRecord,
+    records: StringRecordsIntoIter<Recorder<R>>,
+
+    /// Input which has been read but not yet reported as part of a record
+    unclaimed: Rc<RefCell<Unclaimed>>,
+
+    /// Administrative transactions are rejected unless explicitly allowed
+    allow_admin: bool,
+
+    /// Name of the input, recorded on every row and rejection
+    source: Option<Arc<str>>,
+}
+
+impl<R: Read> TransactionReader<R> {
+    /// Read the header row from the input. The remaining rows are read lazily while iterating
+    pub fn new(reader: R) -> Result<Self, Error> {
+        Self::with_delimiter(reader, b',')
+    }
+
+    /// Read input whose fields are separated by something other than a comma, such as `;`
+    /// or a tab
+    pub fn with_delimiter(reader: R, delimiter: u8) -> Result<Self, Error> {
+        let unclaimed = Rc::new(RefCell::new(Unclaimed::default()));
+        let reader = Recorder {
+            inner: reader,
+            unclaimed: unclaimed.clone(),
+        };
+
+        // Whitespace around fields, a byte order mark, CRLF line endings and rows with
+        // missing or extra trailing columns are all tolerated
+        let mut csv_reader = ReaderBuilder::new()
+            .has_headers(true)
+            .delimiter(delimiter)
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(reader);
+
+        // Headers are matched regardless of case
+        let headers = csv_reader
+            .headers()?
+            .iter()
+            .map(str::to_lowercase)
+            .collect();
+
+        Ok(TransactionReader {
+            headers,
+            records: csv_reader.into_records(),
+            unclaimed,
+            allow_admin: false,
+            source: None,
+        })
+    }
+
+    /// Accept administrative transactions such as unlocks and manual adjustments
+    pub fn allow_admin(mut self, allow: bool) -> Self {
+        self.allow_admin = allow;
+        self
+    }
+
+    /// Read columns under the names the map gives them
+    pub fn map_columns(mut self, columns: &ColumnMap) -> Self {
+        self.headers = self.headers.iter().map(|h| columns.get(h)).collect();
+        self
+    }
+
+    /// Name the input so every row and rejection records where it came from
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(Arc::from(source));
+        self
+    }
+
+    /// Best effort lookup of the transaction id on a record which failed to deserialize
+    fn tx_of(&self, record: &StringRecord) -> Option<Tx> {
+        let idx = self.headers.iter().position(|h| h.trim() == "tx")?;
+        record.get(idx)?.trim().parse().ok()
+    }
+
+    /// Amounts and currencies don't say which field they failed on, so look for the first
+    /// of them which doesn't parse
+    fn unparsable_column(&self, record: &StringRecord) -> Option<usize> {
+        self.headers.iter().zip(record).position(|(header, field)| {
+            !field.is_empty()
+                && match header.trim() {
+                    "amount" => field.parse::<Amount>().is_err(),
+                    "currency" => field.parse::<Currency>().is_err(),
+                    _ => false,
+                }
+        })
+    }
+
+    /// Name of the column a record failed on, or its position when it has no header
+    fn column_of(&self, record: &StringRecord, error: &Error) -> Option<String> {
+        let column = match error {
+            Error::MissingAmount(_) => "amount",
+            Error::MissingOperator(_) => "operator",
+            Error::MissingReason(_) => "reason",
+            Error::MissingRecipient(_) => "to",
+            Error::UnknownTransactionType(_) | Error::AdminNotAllowed(_) => "type",
+            Error::CSVError(e) => {
+                let csv::ErrorKind::Deserialize { err, .. } = e.kind() else {
+                    return None;
+                };
+                let idx = match err.field() {
+                    Some(idx) => usize::try_from(idx).ok()?,
+                    None => self.unparsable_column(record)?,
+                };
+                return Some(
+                    self.headers
+                        .get(idx)
+                        .map_or_else(|| (idx + 1).to_string(), |h| h.trim().to_string()),
+                );
+            }
+            _ => return None,
+        };
+        Some(column.to_string())
+    }
+}
+
+impl<R: Read> Iterator for TransactionReader<R> {
+    type Item = Result<Row, Rejection>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.records.next()? {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                let rejection = Rejection::new(line, String::new(), None, &Error::from(e));
+                return Some(Err(rejection.from_source(self.source.as_deref())));
+            }
+        };
+
+        let line = record.position().map_or(0, |p| p.line());
+
+        // The record runs up to where the parser will start on the next one
+        let start = record.position().map_or(0, |p| p.byte());
+        let end = self.records.reader().position().byte();
+        let raw = self.unclaimed.borrow_mut().claim(start, end);
+
+        let allow_admin = self.allow_admin;
+        let parsed = record
+            .deserialize::<CsvTransaction>(Some(&self.headers))
+            .map_err(Error::from)
+            .and_then(|tx| {
+                let (timestamp, sequence) = (tx.timestamp, tx.sequence);
+                Ok((tx.try_into()?, timestamp, sequence))
+            })
+            .and_then(|(t, timestamp, sequence)| match t {
+                Transaction::Admin { tx, .. } if !allow_admin => Err(Error::AdminNotAllowed(tx)),
+                t => Ok((t, timestamp, sequence)),
+            });
+
+        Some(match parsed {
+            Ok((transaction, timestamp, sequence)) => Ok(Row {
+                line,
+                record: raw,
+                transaction,
+                timestamp,
+                sequence,
+                source: self.source.clone(),
+            }),
+            Err(e) => Err(Rejection::new(line, raw, self.tx_of(&record), &e)
+                .from_source(self.source.as_deref())
+                .at_column(self.column_of(&record, &e))),
+        })
+    }
+}
+
+/// The column rows of several inputs are merged on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeBy {
+    Timestamp,
+    Sequence,
+}
+
+impl MergeBy {
+    fn key(&self, row: &Row) -> Option<u64> {
+        match self {
+            MergeBy::Timestamp => row.timestamp,
+            MergeBy::Sequence => row.sequence,
+        }
+    }
+}
+
+/// Rows of several inputs merged into one stream. Each input should already be in order,
+/// and the row with the lowest key at the head of any input is taken next, with ties going
+/// to the earlier input. Rows without a key and rows which couldn't be read are taken as
+/// soon as they reach the head of their input.
+pub struct Merge<I: Iterator> {
+    inputs: Vec<Peekable<I>>,
+    by: MergeBy,
+}
+
+impl<I> Merge<I>
+where
+    I: Iterator<Item = Result<Row, Rejection>>,
+{
+    pub fn new(inputs: impl IntoIterator<Item = I>, by: MergeBy) -> Self {
+        Merge {
+            inputs: inputs.into_iter().map(Iterator::peekable).collect(),
+            by,
+        }
+    }
+}
+
+impl<I> Iterator for Merge<I>
+where
+    I: Iterator<Item = Result<Row, Rejection>>,
+{
+    type Item = Result<Row, Rejection>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let by = self.by;
+        let mut next: Option<(usize, u64)> = None;
+        for (idx, input) in self.inputs.iter_mut().enumerate() {
+            let key = match input.peek() {
+                None => continue,
+                Some(Ok(row)) => by.key(row),
+                Some(Err(_)) => None,
+            };
+            match key {
+                None => return input.next(),
+                Some(key) if next.is_none_or(|(_, lowest)| key < lowest) => next = Some((idx, key)),
+                Some(_) => (),
+            }
+        }
+
+        let (idx, _) = next?;
+        self.inputs[idx].next()
+    }
+}
+
 /// Final output to standard out
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 struct CsvBalance {
     client: u16,
-    available: f64,
-    held: f64,
-    total: f64,
+    currency: Currency,
+    available: Amount,
+    held: Amount,
+    total: Amount,
     locked: bool,
+    credit_used: Amount,
+    fees: Amount,
 }
 
 impl From<&BalanceSnapshot> for CsvBalance {
     fn from(value: &BalanceSnapshot) -> Self {
         Self {
             client: value.client,
+            currency: value.currency,
             available: value.available,
             held: value.held,
             total: value.total,
             locked: value.locked,
+            credit_used: value.credit_used,
+            fees: value.fees,
         }
     }
 }
@@ -108,19 +632,192 @@ impl From<&BalanceSnapshot> for CsvBalance {
 /// Give a slice of snapshots of the client balances
 pub fn write_balances_to_file(
     balances: &[BalanceSnapshot],
+    format: Format,
+    writer: impl Write,
+) -> Result<(), report::Error> {
+    write_records(balances.iter().map(CsvBalance::from), format, writer)
+}
+
+/// A single account of the trial balance
+#[derive(Debug, Clone, Serialize)]
+struct CsvTrialBalance {
+    currency: Currency,
+    account: String,
+    debit: Amount,
+    credit: Amount,
+    // Empty if the difference can't be represented
+    net: Option<Amount>,
+}
+
+impl From<&TrialBalance> for CsvTrialBalance {
+    fn from(value: &TrialBalance) -> Self {
+        Self {
+            currency: value.currency,
+            account: value.account.to_string(),
+            debit: value.debit,
+            credit: value.credit,
+            net: value.net(),
+        }
+    }
+}
+
+/// Write the totals of every account in the books, one row each
+pub fn write_trial_balance_to_file(
+    lines: &[TrialBalance],
+    format: Format,
+    writer: impl Write,
+) -> Result<(), report::Error> {
+    write_records(lines.iter().map(CsvTrialBalance::from), format, writer)
+}
+
+/// Totals of one currency across every client
+#[derive(Debug, Clone, Serialize)]
+struct CsvSummary {
+    currency: Currency,
+    clients: usize,
+    locked: usize,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    fees: Amount,
+}
+
+impl From<&Summary> for CsvSummary {
+    fn from(value: &Summary) -> Self {
+        Self {
+            currency: value.currency,
+            clients: value.clients,
+            locked: value.locked,
+            available: value.available,
+            held: value.held,
+            total: value.total,
+            fees: value.fees,
+        }
+    }
+}
+
+/// Write the totals of every currency, one row each
+pub fn write_summary_to_file(
+    summaries: &[Summary],
+    format: Format,
     writer: impl Write,
-) -> Result<(), Error> {
-    let mut csv_writer = WriterBuilder::new().from_writer(writer);
+) -> Result<(), report::Error> {
+    write_records(summaries.iter().map(CsvSummary::from), format, writer)
+}
+
+/// A row of the credit limits file
+#[derive(Debug, Deserialize)]
+struct CsvCreditLimit {
+    client: Client,
+    limit: Amount,
+    // Limits without a currency apply to `XXX`, like transactions without one
+    #[serde(default)]
+    currency: Option<Currency>,
+}
 
-    for snapshot in balances {
-        let csv_balance = CsvBalance::from(snapshot);
-        csv_writer.serialize(csv_balance)?;
+/// Read credit limits from CSV with a `client,limit[,currency]` header
+pub fn read_credit_limits(reader: impl Read) -> Result<CreditLimits, Error> {
+    let mut csv_reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+
+    let mut limits = CreditLimits::new();
+    for row in csv_reader.deserialize() {
+        let CsvCreditLimit {
+            client,
+            limit,
+            currency,
+        } = row?;
+        if limit < Amount::ZERO {
+            Err(Error::NegativeCreditLimit(client))?
+        }
+        limits.set(client, currency.unwrap_or_default(), limit);
+    }
+
+    Ok(limits)
+}
+
+/// A row of the fee schedule file
+#[derive(Debug, Deserialize)]
+struct CsvFeeRule {
+    #[serde(rename = "type")]
+    on: FeeTrigger,
+    // A band without a lower bound starts at zero
+    #[serde(default)]
+    min: Option<Amount>,
+    #[serde(default)]
+    max: Option<Amount>,
+    fee: Amount,
+    // Rules without a currency apply to every currency
+    #[serde(default)]
+    currency: Option<Currency>,
+}
+
+/// Read a fee schedule from CSV with a `type,min,max,fee[,currency]` header. Rules are
+/// consulted in the order they appear in the file
+pub fn read_fee_schedule(reader: impl Read) -> Result<FeeSchedule, Error> {
+    let mut csv_reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+
+    let mut fees = FeeSchedule::new();
+    for (idx, row) in csv_reader.deserialize().enumerate() {
+        let CsvFeeRule {
+            on,
+            min,
+            max,
+            fee,
+            currency,
+        } = row?;
+        if fee < Amount::ZERO {
+            Err(Error::NegativeFee(idx + 1))?
+        }
+        fees.push(FeeRule {
+            on,
+            min: min.unwrap_or(Amount::ZERO),
+            max,
+            currency,
+            fee,
+        });
     }
 
-    // Flush the contents of the writer
-    csv_writer.flush()?;
+    Ok(fees)
+}
+
+/// A single entry of a client's history
+#[derive(Debug, Clone, Serialize)]
+struct CsvEntry {
+    client: Client,
+    tx: Tx,
+    #[serde(rename = "type")]
+    t: &'static str,
+    amount: Option<Amount>,
+    currency: Option<Currency>,
+    status: TxStatus,
+    timestamp: Option<Timestamp>,
+    to: Option<Client>,
+    source: Option<String>,
+}
+
+impl From<&Entry> for CsvEntry {
+    fn from(value: &Entry) -> Self {
+        Self {
+            client: *value.t.client(),
+            tx: *value.t.tx(),
+            t: value.t.kind(),
+            amount: value.t.amount(),
+            currency: value.t.currency(),
+            status: value.status(),
+            timestamp: value.at(),
+            to: value.t.recipient(),
+            source: value.source().map(str::to_string),
+        }
+    }
+}
 
-    Ok(())
+/// Write entries from a client's history, one row each
+pub fn write_history_to_file(
+    entries: &[Entry],
+    format: Format,
+    writer: impl Write,
+) -> Result<(), report::Error> {
+    write_records(entries.iter().map(CsvEntry::from), format, writer)
 }
 
 #[cfg(test)]
@@ -131,7 +828,15 @@ mod test {
     use anyhow::{Result, anyhow};
     use csv::Reader;
 
-    use crate::{csv::CsvTransaction, string::StringReader};
+    use crate::{
+        csv::{
+            ColumnMap, CsvTransaction, Error, Merge, MergeBy, TransactionReader, parse_transaction,
+            read_credit_limits, read_fee_schedule,
+        },
+        ledger::{AdminAction, Transaction, amount::Amount, currency::Currency, fees::FeeTrigger},
+        reject::Reason,
+        string::StringReader,
+    };
 
     static EXAMPLE_CSV: &str = r#"
 type, client, tx, amount
@@ -169,7 +874,7 @@ withdrawal, 2, 5, 3.0
         for header in headers.iter() {
             print!(" \"{}\"", header);
         }
-        println!("");
+        println!();
 
         for i in 1..records.len() {
             let csv_transaction: CsvTransaction =
@@ -179,4 +884,342 @@ withdrawal, 2, 5, 3.0
 
         Ok(())
     }
+
+    #[test]
+    fn read_transactions() -> Result<()> {
+        let input =
+            "type,client,tx,amount\ndeposit,1,1,1.5\nwithdrawal,1,2,\nrefund,1,3,\ndispute,1,1,\n";
+        let reader = TransactionReader::new(StringReader::from(input))?;
+
+        let results: Vec<_> = reader.collect();
+        assert_eq!(4, results.len());
+
+        let row = results[0].as_ref().unwrap();
+        assert_eq!(2, row.line);
+        assert_eq!("deposit,1,1,1.5", row.record);
+        assert!(matches!(
+            row.transaction,
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                ..
+            }
+        ));
+
+        let rejection = results[1].as_ref().unwrap_err();
+        assert_eq!(3, rejection.line);
+        assert_eq!(Some(2), rejection.tx);
+        assert_eq!(Reason::MissingAmount, rejection.reason);
+        assert_eq!(Some("amount"), rejection.column.as_deref());
+
+        let rejection = results[2].as_ref().unwrap_err();
+        assert_eq!("refund,1,3,", rejection.record);
+        assert_eq!(Reason::UnknownTransactionType, rejection.reason);
+
+        assert!(matches!(
+            results[3].as_ref().unwrap().transaction,
+            Transaction::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_untidy_input() -> Result<()> {
+        let input = "\u{feff}Type ; Client ; TX ; Amount\r\n Deposit ; 1 ; 1 ; 1.5 ; ;\r\nWITHDRAWAL;1;2;0.5\r\ndispute;1;1\r\n";
+        let rows = TransactionReader::with_delimiter(StringReader::from(input), b';')?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|r| anyhow!("Unexpected rejection {}", r))?;
+
+        assert_eq!(3, rows.len());
+        assert_eq!(
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: "1.5".parse()?,
+                currency: Currency::NONE,
+            },
+            rows[0].transaction
+        );
+        assert!(matches!(
+            rows[1].transaction,
+            Transaction::Withdrawal { tx: 2, .. }
+        ));
+        assert!(matches!(
+            rows[2].transaction,
+            Transaction::Dispute {
+                tx: 1,
+                amount: None,
+                ..
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_are_kept_as_written() -> Result<()> {
+        let input = "\u{feff}type;client;tx;amount;reason\r\n deposit ; 1 ; 1 ; 1.5\r\nrefund;1;2;;\"late; sorry\"\r\nwithdrawal;1;3;x";
+        let results: Vec<_> =
+            TransactionReader::with_delimiter(StringReader::from(input), b';')?.collect();
+
+        assert_eq!(
+            " deposit ; 1 ; 1 ; 1.5",
+            results[0].as_ref().unwrap().record
+        );
+        assert_eq!(
+            "refund;1;2;;\"late; sorry\"",
+            results[1].as_ref().unwrap_err().record
+        );
+        assert_eq!("withdrawal;1;3;x", results[2].as_ref().unwrap_err().record);
+
+        Ok(())
+    }
+
+    #[test]
+    fn map_partner_columns() -> Result<()> {
+        let columns: ColumnMap =
+            "txn_type=type, Customer_ID=client, txn_id=tx, value=amount".parse()?;
+        let input = "txn_type,customer_id,txn_id,value\ndeposit,4,9,2\n";
+        let rows: Vec<_> = TransactionReader::new(StringReader::from(input))?
+            .map_columns(&columns)
+            .collect();
+
+        assert!(matches!(
+            rows[0].as_ref().unwrap().transaction,
+            Transaction::Deposit {
+                client: 4,
+                tx: 9,
+                ..
+            }
+        ));
+        assert!(matches!(
+            "txn_type=kind".parse::<ColumnMap>(),
+            Err(Error::InvalidColumnMap(_))
+        ));
+        assert!(matches!(
+            "txn_type".parse::<ColumnMap>(),
+            Err(Error::InvalidColumnMap(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejections_name_their_column() -> Result<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.5\ndeposit,1,two,1.5\ndeposit,1,3,lots\n";
+        let reader = TransactionReader::new(StringReader::from(input))?.source("monday.csv");
+        let rejections: Vec<_> = reader.filter_map(Result::err).collect();
+
+        assert_eq!(2, rejections.len());
+        assert_eq!(Some("tx"), rejections[0].column.as_deref());
+        assert_eq!(Reason::Malformed, rejections[0].reason);
+        assert_eq!(Some("amount"), rejections[1].column.as_deref());
+        assert!(
+            rejections[1]
+                .to_string()
+                .starts_with("monday.csv, line 4, column amount: ")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_currency_column() -> Result<()> {
+        let input = "type,client,tx,amount,currency\ndeposit,1,1,1.5,eur\ndeposit,1,2,2,\n";
+        let rows: Vec<_> = TransactionReader::new(StringReader::from(input))?.collect();
+
+        assert_eq!(
+            Some("EUR".parse()?),
+            rows[0].as_ref().unwrap().transaction.currency()
+        );
+        assert_eq!(
+            Some(Currency::NONE),
+            rows[1].as_ref().unwrap().transaction.currency()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_bare_records() -> Result<()> {
+        assert!(matches!(
+            parse_transaction("deposit, 3, 7, 2.5, gbp")?,
+            (
+                Transaction::Deposit {
+                    client: 3,
+                    tx: 7,
+                    ..
+                },
+                None
+            )
+        ));
+        assert_eq!(
+            (
+                Transaction::Dispute {
+                    client: 3,
+                    tx: 7,
+                    amount: None,
+                },
+                Some(86400)
+            ),
+            parse_transaction("dispute,3,7,,,,,86400")?
+        );
+        assert_eq!(
+            (
+                Transaction::ChargeBack {
+                    client: 3,
+                    tx: 7,
+                    amount: Some("20".parse()?),
+                },
+                None
+            ),
+            parse_transaction("chargeback,3,7,20")?
+        );
+        assert_eq!(
+            (
+                Transaction::Transfer {
+                    from: 3,
+                    to: 4,
+                    tx: 9,
+                    amount: "1.25".parse()?,
+                    currency: Currency::NONE,
+                },
+                None
+            ),
+            parse_transaction("transfer,3,9,1.25,,,,,4")?
+        );
+        assert!(matches!(
+            parse_transaction("transfer,3,9,1.25"),
+            Err(Error::MissingRecipient(_))
+        ));
+        assert!(matches!(parse_transaction(""), Err(Error::EmptyRecord)));
+        assert!(matches!(
+            parse_transaction("withdrawal,3,8"),
+            Err(Error::MissingAmount(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_timestamps() -> Result<()> {
+        let input = "type,client,tx,amount,timestamp\ndeposit,1,1,1.5,1700000000\ndispute,1,1,,\n";
+
+        let rows: Vec<_> = TransactionReader::new(StringReader::from(input))?.collect();
+        assert_eq!(Some(1_700_000_000), rows[0].as_ref().unwrap().timestamp);
+        assert_eq!(None, rows[1].as_ref().unwrap().timestamp);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_inputs_by_sequence() -> Result<()> {
+        let monday = "type,client,tx,amount,sequence\nwithdrawal,1,1,,1\ndeposit,1,3,1,3\n";
+        let tuesday = "type,client,tx,amount,sequence\ndeposit,1,2,1,2\ndeposit,1,4,1,3\n";
+        let inputs = [
+            TransactionReader::new(StringReader::from(monday))?.source("monday.csv"),
+            TransactionReader::new(StringReader::from(tuesday))?.source("tuesday.csv"),
+        ];
+
+        let rows: Vec<_> = Merge::new(inputs, MergeBy::Sequence).collect();
+        let order: Vec<_> = rows
+            .iter()
+            .map(|row| match row {
+                Ok(row) => *row.transaction.tx(),
+                Err(rejection) => rejection.tx.expect("tx column was readable"),
+            })
+            .collect();
+
+        // Ties go to the earlier input, and each row remembers where it was read from
+        assert_eq!(vec![1, 2, 3, 4], order);
+        let rejection = rows[0].as_ref().unwrap_err();
+        assert_eq!(Some("monday.csv"), rejection.source.as_deref());
+        assert_eq!(2, rejection.line);
+        assert_eq!(
+            Some("tuesday.csv"),
+            rows[1].as_ref().unwrap().source.as_deref()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_limits() -> Result<()> {
+        let input = "client, limit, currency\n1, 500, EUR\n2, 100,\n";
+        let limits = read_credit_limits(StringReader::from(input))?;
+
+        assert_eq!("500".parse::<Amount>()?, limits.get(1, "EUR".parse()?));
+        assert_eq!(Amount::ZERO, limits.get(1, Currency::NONE));
+        assert_eq!("100".parse::<Amount>()?, limits.get(2, Currency::NONE));
+
+        assert!(matches!(
+            read_credit_limits(StringReader::from("client,limit\n3,-1\n")),
+            Err(Error::NegativeCreditLimit(3))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_fees() -> Result<()> {
+        let input = "type, min, max, fee, currency\nwithdrawal, , 1000, 1.50,\nwithdrawal, 1000, , 5, EUR\nchargeback, , , 25,\n";
+        let fees = read_fee_schedule(StringReader::from(input))?;
+
+        let eur = "EUR".parse()?;
+        assert_eq!(
+            Some("1.5".parse::<Amount>()?),
+            fees.fee_for(FeeTrigger::Withdrawal, "999.9999".parse()?, eur)
+        );
+        assert_eq!(
+            Some("5".parse::<Amount>()?),
+            fees.fee_for(FeeTrigger::Withdrawal, "1000".parse()?, eur)
+        );
+        assert_eq!(
+            None,
+            fees.fee_for(FeeTrigger::Withdrawal, "1000".parse()?, Currency::NONE)
+        );
+        assert_eq!(
+            None,
+            fees.fee_for(FeeTrigger::Dispute, "10".parse()?, Currency::NONE)
+        );
+
+        assert!(matches!(
+            read_fee_schedule(StringReader::from("type,min,max,fee\ndispute,,,-1\n")),
+            Err(Error::NegativeFee(1))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn admin_transactions_are_opt_in() -> Result<()> {
+        let input = "type,client,tx,amount,currency,operator,reason\nunlock,1,9,,,42,cleared by risk\ncredit,1,10,5,,42,\n";
+
+        let rows: Vec<_> = TransactionReader::new(StringReader::from(input))?.collect();
+        assert_eq!(
+            Reason::AdminNotAllowed,
+            rows[0].as_ref().unwrap_err().reason
+        );
+
+        let rows: Vec<_> = TransactionReader::new(StringReader::from(input))?
+            .allow_admin(true)
+            .collect();
+        assert!(matches!(
+            &rows[0].as_ref().unwrap().transaction,
+            Transaction::Admin {
+                action: AdminAction::Unlock,
+                operator: 42,
+                reason,
+                ..
+            } if reason == "cleared by risk"
+        ));
+        assert_eq!(Reason::MissingReason, rows[1].as_ref().unwrap_err().reason);
+
+        Ok(())
+    }
 }
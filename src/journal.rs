@@ -0,0 +1,697 @@
+//! Append only journal of every transaction the ledger has accepted. The journal gives
+//! durability between saved states: on startup it is replayed to rebuild the ledger, and a
+//! record which was only partially written when the process died is cut off the end.
+//!
+//! Each record is laid out as
+//!
+//! ```text
+//! | length: u32 LE | crc32 of length: u32 LE | crc32 of payload: u32 LE | payload: JSON transaction |
+//! ```
+//!
+//! The length has a checksum of its own so a corrupt length is never mistaken for a
+//! record which runs past the end of the file.
+//!
+//! The payload carries an `at` field alongside the transaction's own fields when the
+//! transaction had a timestamp. A transaction the ledger refused may still have moved its
+//! clock on and settled expired disputes, which is journaled as a `{"tick": at}` payload.
+//!
+//! Fees are charged under the [`FeeSchedule`] of the run which journaled the transaction,
+//! not the one replaying it. The schedule is journaled as a `{"fees": [...]}` payload
+//! whenever it changes between runs, and replay switches to it at that point.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::ledger::{Ledger, Timestamp, Transaction, fees::FeeSchedule};
+
+/// Size of the length and checksums which precede every payload
+const HEADER_LEN: u64 = 12;
+
+#[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    #[error("Corrupt journal record at byte {0}")]
+    CorruptRecord(u64),
+
+    #[error("Invalid fsync policy: {0}")]
+    InvalidPolicy(String),
+
+    #[error("I/O Error: {0}")]
+    IOError(#[from] io::Error),
+
+    #[error("Journal Serialization Error: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+/// The payload of a single record. Records are written from a borrowed transaction
+#[derive(Debug, Serialize, Deserialize)]
+struct Record<T = Transaction, S = String> {
+    #[serde(flatten)]
+    t: T,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    at: Option<Timestamp>,
+
+    /// The input the transaction was read from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<S>,
+}
+
+/// Every payload is either a transaction, a tick of the clock or a fee schedule
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Tick { tick: Timestamp },
+    Fees { fees: FeeSchedule },
+    Record(Record),
+}
+
+#[derive(Serialize)]
+struct Tick {
+    tick: Timestamp,
+}
+
+#[derive(Serialize)]
+struct Fees<'a> {
+    fees: &'a FeeSchedule,
+}
+
+/// How often appended records are forced to stable storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// Sync after every record. Nothing acknowledged is ever lost
+    #[default]
+    Always,
+    /// Sync once every N records. At most N - 1 acknowledged records are lost on a crash
+    Every(u32),
+    /// Leave flushing to the operating system
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = Error;
+
+    /// Accepts `always`, `never` or a record count such as `100`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => match s.parse::<u32>() {
+                Ok(0) | Err(_) => Err(Error::InvalidPolicy(s.to_string())),
+                Ok(1) => Ok(FsyncPolicy::Always),
+                Ok(n) => Ok(FsyncPolicy::Every(n)),
+            },
+        }
+    }
+}
+
+/// What was found while replaying the journal
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Replay {
+    /// Records applied to the ledger
+    pub applied: usize,
+
+    /// Records the ledger refused. This happens when the journal overlaps a saved state
+    /// which already contains the transaction, or for transactions the server journaled
+    /// ahead of the ledger refusing them
+    pub skipped: usize,
+
+    /// Bytes of a torn final record which were cut off the end of the file
+    pub truncated: u64,
+}
+
+pub struct Journal {
+    file: File,
+    policy: FsyncPolicy,
+
+    /// Records appended since the last sync
+    unsynced: u32,
+
+    /// The schedule fees are charged under by this run
+    fees: FeeSchedule,
+}
+
+impl Journal {
+    /// Open the journal at `path`, creating it if needed, and replay every record in it
+    /// onto `ledger`. A torn final record is truncated so new records follow the last
+    /// complete one. Records are replayed under the fee schedules they were journaled
+    /// with, and the ledger's own schedule is journaled if it differs from the last one.
+    pub fn open(
+        path: impl AsRef<Path>,
+        policy: FsyncPolicy,
+        ledger: &mut Ledger,
+    ) -> Result<(Journal, Replay), Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let len = file.metadata()?.len();
+        let fees = ledger.fee_schedule().clone();
+        let (mut replay, end, journaled) = Self::replay_records(&mut file, len, ledger)?;
+
+        if end < len {
+            replay.truncated = len - end;
+            file.set_len(end)?;
+            file.sync_all()?;
+        }
+
+        file.seek(SeekFrom::Start(end))?;
+
+        let mut journal = Journal {
+            file,
+            policy,
+            unsynced: 0,
+            fees,
+        };
+        if journaled.as_ref() != Some(&journal.fees) {
+            journal.write_fees()?;
+        }
+
+        Ok((journal, replay))
+    }
+
+    /// Replay every record in the journal at `path` onto `ledger` without writing to it,
+    /// for reading a ledger back rather than carrying it on. A torn final record is left
+    /// in place, and counted as truncated as it's skipped all the same
+    pub fn replay(path: impl AsRef<Path>, ledger: &mut Ledger) -> Result<Replay, Error> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let (mut replay, end, _) = Self::replay_records(&mut file, len, ledger)?;
+        replay.truncated = len - end;
+
+        Ok(replay)
+    }
+
+    /// Apply every complete record in `file` to `ledger`. Returns what was found, the
+    /// offset just past the last complete record and the last schedule journaled. The
+    /// ledger is left charging fees under the schedule it had beforehand
+    fn replay_records(
+        file: &mut File,
+        len: u64,
+        ledger: &mut Ledger,
+    ) -> Result<(Replay, u64, Option<FeeSchedule>), Error> {
+        let mut replay = Replay::default();
+        let mut end = 0;
+
+        // Journals written before schedules were journaled are replayed under this run's
+        let fees = ledger.fee_schedule().clone();
+        let mut journaled = None;
+
+        let mut reader = BufReader::new(file);
+        while let Some(payload) = Self::read_record(&mut reader, end, len)? {
+            let applied = match payload {
+                Payload::Tick { tick } => Some(ledger.advance_clock(tick)),
+                Payload::Fees { fees } => {
+                    ledger.replace_fee_schedule(fees.clone());
+                    journaled = Some(fees);
+                    None
+                }
+                Payload::Record(Record { t, at, source }) => {
+                    Some(ledger.process_transaction_from(t, at, source.as_deref()))
+                }
+            };
+            match applied {
+                Some(Ok(())) => replay.applied += 1,
+                Some(Err(_)) => replay.skipped += 1,
+                None => (),
+            }
+            end = reader.stream_position()?;
+        }
+        ledger.replace_fee_schedule(fees);
+
+        Ok((replay, end, journaled))
+    }
+
+    /// Read the record starting at `offset`. `None` is returned at the end of the file or
+    /// when the remaining bytes are a torn record.
+    fn read_record(
+        reader: &mut impl Read,
+        offset: u64,
+        len: u64,
+    ) -> Result<Option<Payload>, Error> {
+        if len - offset < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+        let word = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().expect("4 bytes"));
+        let (payload_len, len_crc, crc) = (word(0), word(4), word(8));
+
+        if crc32fast::hash(&header[0..4]) != len_crc {
+            // Space allocated on disk without its contents being written reads back as
+            // zeros. Anything else means the length itself is corrupt.
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest)?;
+            if header.iter().chain(&rest).all(|b| *b == 0) {
+                return Ok(None);
+            }
+            Err(Error::CorruptRecord(offset))?
+        }
+
+        // The length is known to be good, so the payload was cut short by a crash
+        let record_end = offset + HEADER_LEN + u64::from(payload_len);
+        if record_end > len {
+            return Ok(None);
+        }
+
+        let mut payload = vec![0u8; payload_len as usize];
+        reader.read_exact(&mut payload)?;
+
+        if crc32fast::hash(&payload) != crc {
+            // The final record may have been allocated on disk without its contents
+            // being written. Anything further into the file means real corruption.
+            if record_end == len {
+                return Ok(None);
+            }
+            Err(Error::CorruptRecord(offset))?
+        }
+
+        let record = serde_json::from_slice(&payload).map_err(|_| Error::CorruptRecord(offset))?;
+
+        Ok(Some(record))
+    }
+
+    /// Durably record a transaction the ledger has accepted along with when it happened
+    /// and the input it was read from. The transaction should only be acknowledged once
+    /// this returns successfully.
+    pub fn append(
+        &mut self,
+        t: &Transaction,
+        at: Option<Timestamp>,
+        source: Option<&str>,
+    ) -> Result<(), Error> {
+        self.write(&Record { t, at, source })
+    }
+
+    /// Durably record the clock moving forward to `at` for a transaction the ledger
+    /// refused, so that disputes it settled are settled again on replay
+    pub fn tick(&mut self, at: Timestamp) -> Result<(), Error> {
+        self.write(&Tick { tick: at })
+    }
+
+    /// Record the schedule later records are charged fees under
+    fn write_fees(&mut self) -> Result<(), Error> {
+        let fees = Fees { fees: &self.fees };
+        let payload = serde_json::to_vec(&fees)?;
+        self.write_payload(payload)
+    }
+
+    fn write(&mut self, payload: &impl Serialize) -> Result<(), Error> {
+        let payload = serde_json::to_vec(payload)?;
+        self.write_payload(payload)
+    }
+
+    fn write_payload(&mut self, payload: Vec<u8>) -> Result<(), Error> {
+        let payload_len = u32::try_from(payload.len()).expect("Transactions are small");
+
+        let mut record = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+        let payload_len = payload_len.to_le_bytes();
+        record.extend_from_slice(&payload_len);
+        record.extend_from_slice(&crc32fast::hash(&payload_len).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        // A single write keeps a crash from interleaving a header with a different payload
+        self.file.write_all(&record)?;
+
+        self.unsynced += 1;
+        match self.policy {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::Every(n) if self.unsynced >= n => self.sync()?,
+            FsyncPolicy::Every(_) | FsyncPolicy::Never => (),
+        }
+
+        Ok(())
+    }
+
+    /// Force every appended record to stable storage
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+
+        Ok(())
+    }
+
+    /// Empty the journal once the ledger has been saved elsewhere. The saved state
+    /// must be durable before this is called.
+    pub fn checkpoint(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_fees()?;
+        self.sync()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::{self, OpenOptions},
+        io::Write,
+        path::PathBuf,
+    };
+
+    use anyhow::Result;
+
+    use crate::{
+        journal::{Error, FsyncPolicy, Journal},
+        ledger::{
+            Ledger, Transaction, TxStatus,
+            amount::Amount,
+            currency::Currency,
+            fees::{FeeRule, FeeSchedule, FeeTrigger},
+            window::{DAY, DisputeWindow},
+        },
+    };
+
+    fn journal_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "transactor-journal-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn deposit(tx: u32) -> Transaction {
+        Transaction::Deposit {
+            client: 0,
+            tx,
+            amount: "10".parse().unwrap(),
+            currency: Currency::NONE,
+        }
+    }
+
+    #[test]
+    fn replay_rebuilds_ledger() -> Result<()> {
+        let path = journal_path("replay");
+
+        {
+            let mut ledger = Ledger::new();
+            let (mut journal, replay) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+            assert_eq!(0, replay.applied);
+
+            for t in [
+                deposit(1),
+                deposit(2),
+                Transaction::Dispute {
+                    client: 0,
+                    tx: 1,
+                    amount: None,
+                },
+            ] {
+                ledger.process_transaction(t.clone())?;
+                journal.append(&t, None, None)?;
+            }
+        }
+
+        let mut ledger = Ledger::new();
+        let (_, replay) = Journal::open(&path, FsyncPolicy::Never, &mut ledger)?;
+        assert_eq!(3, replay.applied);
+        assert_eq!(0, replay.truncated);
+        assert_eq!(Some("10".parse()?), ledger.get_available_balance(0));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn timestamps_and_sources_are_replayed() -> Result<()> {
+        let path = journal_path("timestamps");
+
+        {
+            let mut ledger = Ledger::new();
+            let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+            journal.append(&deposit(1), Some(1_700_000_000), Some("monday.csv"))?;
+            journal.append(&deposit(2), None, None)?;
+        }
+
+        let mut ledger = Ledger::new();
+        let (_, replay) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+        assert_eq!(2, replay.applied);
+        assert_eq!(Some(1_700_000_000), ledger.clock());
+        let entry = ledger.get_entry(0, 1)?.expect("deposit was replayed");
+        assert_eq!(Some("monday.csv"), entry.source());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn ticks_settle_disputes_on_replay() -> Result<()> {
+        let path = journal_path("ticks");
+        let windowed = || {
+            Ledger::new().with_dispute_window(DisputeWindow {
+                resolve_within: Some(30 * DAY),
+                ..Default::default()
+            })
+        };
+        let dispute = Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
+        };
+        let withdrawal = Transaction::Withdrawal {
+            client: 2,
+            tx: 2,
+            amount: "5".parse()?,
+            currency: Currency::NONE,
+        };
+
+        {
+            let mut ledger = windowed();
+            let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+            for t in [deposit(1), dispute] {
+                ledger.process_transaction_at(t.clone(), Some(0))?;
+                journal.append(&t, Some(0), None)?;
+            }
+
+            // Refused, but late enough to settle the dispute
+            assert!(
+                ledger
+                    .process_transaction_at(withdrawal, Some(60 * DAY))
+                    .is_err()
+            );
+            journal.tick(60 * DAY)?;
+        }
+
+        let mut ledger = windowed();
+        let (_, replay) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+        assert_eq!(3, replay.applied);
+        let entry = ledger.get_entry(0, 1)?.expect("deposit was replayed");
+        assert_eq!(TxStatus::Resolved, entry.status());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn fees_are_replayed_under_their_schedule() -> Result<()> {
+        let path = journal_path("fees");
+        let mut fees = FeeSchedule::new();
+        fees.push(FeeRule {
+            on: FeeTrigger::Withdrawal,
+            min: Amount::ZERO,
+            max: None,
+            currency: None,
+            fee: "1".parse()?,
+        });
+        let withdrawal = |tx| Transaction::Withdrawal {
+            client: 0,
+            tx,
+            amount: "2".parse().unwrap(),
+            currency: Currency::NONE,
+        };
+
+        {
+            let mut ledger = Ledger::new().with_fee_schedule(fees.clone());
+            let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+            for t in [deposit(1), withdrawal(2)] {
+                ledger.process_transaction(t.clone())?;
+                journal.append(&t, None, None)?;
+            }
+        }
+
+        // A run without fees still replays the fee charged by the run before it
+        {
+            let mut ledger = Ledger::new();
+            let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+            assert_eq!(Some("7".parse()?), ledger.get_available_balance(0));
+            ledger.process_transaction(withdrawal(3))?;
+            journal.append(&withdrawal(3), None, None)?;
+        }
+
+        // Each withdrawal is charged as it was when it was first applied
+        let mut ledger = Ledger::new().with_fee_schedule(fees);
+        let (_, replay) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+        assert_eq!(3, replay.applied);
+        assert_eq!(Some("5".parse()?), ledger.get_available_balance(0));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn torn_final_record_is_truncated() -> Result<()> {
+        let path = journal_path("torn");
+
+        {
+            let mut ledger = Ledger::new();
+            let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+            journal.append(&deposit(1), None, None)?;
+        }
+
+        // Simulate a crash part way through writing the next record
+        let complete = fs::metadata(&path)?.len();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(&[40, 0, 0, 0, 1, 2, 3])?;
+
+        let mut ledger = Ledger::new();
+        let (mut journal, replay) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+        assert_eq!(1, replay.applied);
+        assert_eq!(7, replay.truncated);
+        assert_eq!(complete, fs::metadata(&path)?.len());
+
+        // New records follow on from the last complete one
+        journal.append(&deposit(2), None, None)?;
+        drop(journal);
+
+        let mut ledger = Ledger::new();
+        let (_, replay) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+        assert_eq!(2, replay.applied);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn replay_leaves_the_journal_as_it_is() -> Result<()> {
+        let path = journal_path("read-only");
+
+        {
+            let mut ledger = Ledger::new();
+            let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+            journal.append(&deposit(1), None, None)?;
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(&[40, 0, 0, 0, 1, 2, 3])?;
+        let written = fs::read(&path)?;
+
+        // Neither a torn record nor a schedule of its own is written back
+        let mut fees = FeeSchedule::new();
+        fees.push(FeeRule {
+            on: FeeTrigger::Withdrawal,
+            min: Amount::ZERO,
+            max: None,
+            currency: None,
+            fee: "1".parse()?,
+        });
+        let mut ledger = Ledger::new().with_fee_schedule(fees.clone());
+        let replay = Journal::replay(&path, &mut ledger)?;
+        assert_eq!(1, replay.applied);
+        assert_eq!(7, replay.truncated);
+        assert_eq!(Some("10".parse()?), ledger.get_available_balance(0));
+        assert_eq!(&fees, ledger.fee_schedule());
+        assert_eq!(written, fs::read(&path)?);
+
+        // A journal which isn't there isn't created
+        fs::remove_file(&path)?;
+        assert!(matches!(
+            Journal::replay(&path, &mut Ledger::new()),
+            Err(Error::IOError(_))
+        ));
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn corruption_before_the_end_is_an_error() -> Result<()> {
+        let path = journal_path("corrupt");
+
+        {
+            let mut ledger = Ledger::new();
+            let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+            journal.append(&deposit(1), None, None)?;
+            journal.append(&deposit(2), None, None)?;
+        }
+
+        // Flip a byte within the first payload
+        let mut bytes = fs::read(&path)?;
+        bytes[14] ^= 0xff;
+        fs::write(&path, bytes)?;
+
+        let mut ledger = Ledger::new();
+        assert!(matches!(
+            Journal::open(&path, FsyncPolicy::Always, &mut ledger),
+            Err(Error::CorruptRecord(0))
+        ));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_length_is_an_error() -> Result<()> {
+        let path = journal_path("length");
+
+        {
+            let mut ledger = Ledger::new();
+            let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+            journal.append(&deposit(1), None, None)?;
+            journal.append(&deposit(2), None, None)?;
+        }
+
+        // A length running past the end of the file mustn't cut off the records after it
+        let mut bytes = fs::read(&path)?;
+        bytes[3] = 0x7f;
+        fs::write(&path, &bytes)?;
+
+        let mut ledger = Ledger::new();
+        assert!(matches!(
+            Journal::open(&path, FsyncPolicy::Always, &mut ledger),
+            Err(Error::CorruptRecord(0))
+        ));
+        assert_eq!(bytes.len() as u64, fs::metadata(&path)?.len());
+
+        // Space which was allocated but never written is still a torn tail
+        let complete = bytes.len() as u64;
+        bytes[3] = 0;
+        bytes.extend_from_slice(&[0; 32]);
+        fs::write(&path, &bytes)?;
+
+        let mut ledger = Ledger::new();
+        let (_, replay) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+        assert_eq!(2, replay.applied);
+        assert_eq!(32, replay.truncated);
+        assert_eq!(complete, fs::metadata(&path)?.len());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn parse_policy() -> Result<()> {
+        assert_eq!(FsyncPolicy::Always, "always".parse()?);
+        assert_eq!(FsyncPolicy::Never, "never".parse()?);
+        assert_eq!(FsyncPolicy::Every(100), "100".parse()?);
+        assert!("0".parse::<FsyncPolicy>().is_err());
+
+        Ok(())
+    }
+}
@@ -1,12 +1,43 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    sync::Arc,
+};
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::ledger::balance::{Balance, BalanceSnapshot};
-
+use crate::ledger::{
+    amount::Amount,
+    balance::{Balance, BalanceSnapshot},
+    books::{Account, Books},
+    currency::Currency,
+    fees::{FeeSchedule, FeeTrigger},
+    limits::CreditLimits,
+    policy::{ChargeBackEffect, DisputePolicy, StandardPolicy},
+    store::{MemoryStore, TransactionStore},
+    window::DisputeWindow,
+};
+
+pub mod amount;
 pub mod balance;
+pub mod books;
+pub mod currency;
+pub mod fees;
+pub mod history;
+pub mod limits;
+pub mod policy;
+pub mod represent;
+pub mod shard;
+pub mod snapshot;
+pub mod state;
+pub mod store;
+pub mod summary;
+pub mod transfer;
+pub mod window;
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Duplicate transaction id: {0}")]
     DuplicateTransaction(Tx),
@@ -20,8 +51,38 @@ pub enum Error {
     #[error("Client account is frozen: {0}")]
     FrozenAccountError(Client),
 
+    #[error("Client account is not frozen: {0}")]
+    AccountNotFrozen(Client),
+
+    #[error("Transaction can't be disputed: {0}")]
+    NotDisputable(Tx),
+
+    #[error("Dispute window has closed on transaction: {0}")]
+    DisputeWindowClosed(Tx),
+
+    #[error("Disputed amount must be positive on transaction: {0}")]
+    InvalidDisputeAmount(Tx),
+
+    #[error("Disputed amount exceeds what's left on transaction: {0}")]
+    DisputeAmountExceeded(Tx),
+
+    #[error("Transaction can't transfer to the client sending it: {0}")]
+    SelfTransfer(Tx),
+
+    #[error("Transferred amount must be positive on transaction: {0}")]
+    InvalidTransferAmount(Tx),
+
+    #[error("Transfers can't be split between worker threads: {0}")]
+    ShardedTransfer(Tx),
+
+    #[error("Client has run out of fee transaction ids: {0}")]
+    FeeTxExhausted(Client),
+
     #[error(transparent)]
     BalanceError(#[from] balance::Error),
+
+    #[error(transparent)]
+    StoreError(#[from] store::Error),
 }
 
 /// UserId alias for ease of reading
@@ -30,14 +91,93 @@ pub type Client = u16;
 /// Transaction id alias for ease of reading
 pub type Tx = u32;
 
+/// Id of the operator who issued an administrative transaction
+pub type Operator = u32;
+
+/// Seconds since the Unix epoch
+pub type Timestamp = u64;
+
+/// Administrative actions taken on a client's account by an operator
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminAction {
+    /// Lift the freeze placed on an account, for example by a charge back
+    Unlock,
+    /// Freeze the account against all further client transactions
+    Freeze,
+    /// Manually add funds to a balance. This ignores any freeze on the account
+    Credit { amount: Amount, currency: Currency },
+    /// Manually remove funds from a balance. This ignores any freeze on the account and
+    /// may leave the balance negative
+    Debit { amount: Amount, currency: Currency },
+}
+
 /// Each of the individual operations which we may process
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum Transaction {
-    Deposit { client: Client, tx: Tx, amount: f64 },
-    Withdrawal { client: Client, tx: Tx, amount: f64 },
-    Dispute { client: Client, tx: Tx },
-    Resolve { client: Client, tx: Tx },
-    ChargeBack { client: Client, tx: Tx },
+    Deposit {
+        client: Client,
+        tx: Tx,
+        amount: Amount,
+        currency: Currency,
+    },
+    Withdrawal {
+        client: Client,
+        tx: Tx,
+        amount: Amount,
+        currency: Currency,
+    },
+    /// Move money from one client's balance to another's. The transfer belongs to the
+    /// sender, and disputes on it are raised by the sender
+    Transfer {
+        from: Client,
+        to: Client,
+        tx: Tx,
+        amount: Amount,
+        currency: Currency,
+    },
+    /// Dispute `amount` of an entry, or everything not yet disputed when absent. An entry
+    /// may be disputed in several parts, up to its own amount
+    Dispute {
+        client: Client,
+        tx: Tx,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        amount: Option<Amount>,
+    },
+    /// Release `amount` of an open dispute, or all of it when absent
+    Resolve {
+        client: Client,
+        tx: Tx,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        amount: Option<Amount>,
+    },
+    /// Charge back `amount` of an open dispute, or all of it when absent
+    ChargeBack {
+        client: Client,
+        tx: Tx,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        amount: Option<Amount>,
+    },
+    /// The merchant won the charge back on an entry back through representment
+    Represent { client: Client, tx: Tx },
+    Admin {
+        client: Client,
+        tx: Tx,
+        action: AdminAction,
+        operator: Operator,
+        reason: String,
+    },
+    /// Generated by the ledger when a transaction matches a rule of the [`FeeSchedule`].
+    /// Never read from input
+    Fee {
+        client: Client,
+        tx: Tx,
+        amount: Amount,
+        currency: Currency,
+        /// The transaction the fee was charged for
+        charged_for: Tx,
+    },
 }
 
 impl Transaction {
@@ -46,9 +186,13 @@ impl Transaction {
         match self {
             Transaction::Deposit { client, .. } => client,
             Transaction::Withdrawal { client, .. } => client,
+            Transaction::Transfer { from, .. } => from,
             Transaction::Dispute { client, .. } => client,
             Transaction::Resolve { client, .. } => client,
             Transaction::ChargeBack { client, .. } => client,
+            Transaction::Represent { client, .. } => client,
+            Transaction::Admin { client, .. } => client,
+            Transaction::Fee { client, .. } => client,
         }
     }
 
@@ -57,24 +201,122 @@ impl Transaction {
         match self {
             Transaction::Deposit { tx, .. } => tx,
             Transaction::Withdrawal { tx, .. } => tx,
+            Transaction::Transfer { tx, .. } => tx,
             Transaction::Dispute { tx, .. } => tx,
             Transaction::Resolve { tx, .. } => tx,
             Transaction::ChargeBack { tx, .. } => tx,
+            Transaction::Represent { tx, .. } => tx,
+            Transaction::Admin { tx, .. } => tx,
+            Transaction::Fee { tx, .. } => tx,
+        }
+    }
+
+    /// The currency of a deposit, withdrawal, transfer or fee. Other transactions take the
+    /// currency of the transaction they refer to
+    pub fn currency(&self) -> Option<Currency> {
+        match self {
+            Transaction::Deposit { currency, .. } => Some(*currency),
+            Transaction::Withdrawal { currency, .. } => Some(*currency),
+            Transaction::Transfer { currency, .. } => Some(*currency),
+            Transaction::Admin {
+                action: AdminAction::Credit { currency, .. } | AdminAction::Debit { currency, .. },
+                ..
+            } => Some(*currency),
+            Transaction::Fee { currency, .. } => Some(*currency),
+            _ => None,
+        }
+    }
+
+    /// The name of the transaction type as it's written in the input
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Transaction::Deposit { .. } => "deposit",
+            Transaction::Withdrawal { .. } => "withdrawal",
+            Transaction::Transfer { .. } => "transfer",
+            Transaction::Dispute { .. } => "dispute",
+            Transaction::Resolve { .. } => "resolve",
+            Transaction::ChargeBack { .. } => "chargeback",
+            Transaction::Represent { .. } => "represent",
+            Transaction::Admin { action, .. } => match action {
+                AdminAction::Unlock => "unlock",
+                AdminAction::Freeze => "freeze",
+                AdminAction::Credit { .. } => "credit",
+                AdminAction::Debit { .. } => "debit",
+            },
+            Transaction::Fee { .. } => "fee",
+        }
+    }
+
+    /// The amount of money moved by a deposit, withdrawal, transfer, manual adjustment or fee
+    pub fn amount(&self) -> Option<Amount> {
+        match self {
+            Transaction::Deposit { amount, .. } => Some(*amount),
+            Transaction::Withdrawal { amount, .. } => Some(*amount),
+            Transaction::Transfer { amount, .. } => Some(*amount),
+            Transaction::Admin {
+                action: AdminAction::Credit { amount, .. } | AdminAction::Debit { amount, .. },
+                ..
+            } => Some(*amount),
+            Transaction::Fee { amount, .. } => Some(*amount),
+            _ => None,
+        }
+    }
+
+    /// The client who received a transfer
+    pub fn recipient(&self) -> Option<Client> {
+        match self {
+            Transaction::Transfer { to, .. } => Some(*to),
+            _ => None,
         }
     }
 
+    /// Transactions which are registered as entries within the ledger. Every other
+    /// transaction refers back to one of these
+    fn is_entry(&self) -> bool {
+        matches!(
+            self,
+            Transaction::Deposit { .. }
+                | Transaction::Withdrawal { .. }
+                | Transaction::Transfer { .. }
+                | Transaction::Admin { .. }
+                | Transaction::Fee { .. }
+        )
+    }
+
     fn key(&self) -> (Client, Tx) {
         match self {
             Transaction::Deposit { client, tx, .. } => (*client, *tx),
             Transaction::Withdrawal { client, tx, .. } => (*client, *tx),
-            Transaction::Dispute { client, tx } => (*client, *tx),
-            Transaction::Resolve { client, tx } => (*client, *tx),
-            Transaction::ChargeBack { client, tx } => (*client, *tx),
+            Transaction::Transfer { from, tx, .. } => (*from, *tx),
+            Transaction::Dispute { client, tx, .. } => (*client, *tx),
+            Transaction::Resolve { client, tx, .. } => (*client, *tx),
+            Transaction::ChargeBack { client, tx, .. } => (*client, *tx),
+            Transaction::Represent { client, tx } => (*client, *tx),
+            Transaction::Admin { client, tx, .. } => (*client, *tx),
+            Transaction::Fee { client, tx, .. } => (*client, *tx),
+        }
+    }
+
+    fn entry_key(&self) -> EntryKey {
+        let (client, tx) = self.key();
+        match self {
+            Transaction::Fee { .. } => EntryKey::Fee(client, tx),
+            _ => EntryKey::Transaction(client, tx),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The key an entry is registered under. The ledger numbers the fees it posts on its own,
+/// so their ids are kept apart from the ids input transactions are free to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EntryKey {
+    /// A deposit, withdrawal, transfer or administrative transaction
+    Transaction(Client, Tx),
+    /// A fee posted by the ledger
+    Fee(Client, Tx),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TxStatus {
     /// All valid transactions are registered with an active status
     #[default]
@@ -85,6 +327,8 @@ pub enum TxStatus {
     Resolved,
     /// The transaction has been charged back and the balance has been removed
     ChargedBack,
+    /// The charge back was reversed by representment and the balance has been restored
+    Represented,
 }
 
 impl Display for TxStatus {
@@ -94,223 +338,926 @@ impl Display for TxStatus {
             TxStatus::Disputed => "Disputed",
             TxStatus::Resolved => "Resolved",
             TxStatus::ChargedBack => "ChargedBack",
+            TxStatus::Represented => "Represented",
         };
         f.write_str(s)
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Entry {
+/// A deposit, withdrawal or administrative transaction registered in the ledger, along
+/// with where it stands in the dispute process
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
     /// The transaction for this entry
     pub t: Transaction,
 
     /// The status of this transaction
     status: TxStatus,
+
+    /// When the transaction happened, if the input carried timestamps
+    #[serde(default)]
+    at: Option<Timestamp>,
+
+    /// When an open dispute on this transaction must be settled by
+    #[serde(default)]
+    deadline: Option<Timestamp>,
+
+    /// How much of the transaction has been disputed, across every dispute raised on it
+    #[serde(default)]
+    disputed: Amount,
+
+    /// How much of what's been disputed is still waiting to be resolved or charged back
+    #[serde(default)]
+    open: Amount,
+
+    /// How much of the transaction has been charged back
+    #[serde(default)]
+    charged_back: Amount,
+
+    /// The input the transaction was read from, if it's known. Interned by the ledger so
+    /// every entry read from one input shares its name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<Arc<str>>,
 }
 
 impl Entry {
-    fn new(t: Transaction) -> Self {
+    fn new(t: Transaction, at: Option<Timestamp>) -> Self {
         Entry {
             t,
             status: TxStatus::Active,
+            at,
+            deadline: None,
+            disputed: Amount::ZERO,
+            open: Amount::ZERO,
+            charged_back: Amount::ZERO,
+            source: None,
         }
     }
 
-    fn dispute(&mut self) -> Result<(), Error> {
-        if self.status != TxStatus::Active {
-            Err(Error::UnexpectedTxStatus(self.status))?
-        }
+    pub fn status(&self) -> TxStatus {
+        self.status
+    }
 
+    /// The key the entry is registered under
+    pub fn key(&self) -> EntryKey {
+        self.t.entry_key()
+    }
+
+    /// When the transaction happened, if it's known
+    pub fn at(&self) -> Option<Timestamp> {
+        self.at
+    }
+
+    /// The input the transaction was read from, if it's known
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    /// When the open dispute on this entry must be settled by, if it has a deadline
+    pub fn deadline(&self) -> Option<Timestamp> {
+        self.deadline
+    }
+
+    /// How much of the transaction has been disputed so far
+    pub fn disputed(&self) -> Amount {
+        self.disputed
+    }
+
+    /// How much of the transaction is in dispute right now
+    pub fn open(&self) -> Amount {
+        self.open
+    }
+
+    /// Dispute `amount` more of the transaction. Without an amount everything not yet
+    /// disputed is, which is only allowed while no dispute is open. Returns the amount
+    /// which is now disputed
+    fn dispute(&mut self, amount: Option<Amount>) -> Result<Amount, Error> {
+        let tx = *self.t.tx();
+        let remaining = self
+            .t
+            .amount()
+            .unwrap_or(Amount::ZERO)
+            .checked_sub(self.disputed)
+            .ok_or(balance::Error::Overflow)?;
+
+        let amount = match (self.status, amount) {
+            (TxStatus::Active, None) => remaining,
+            (TxStatus::Active | TxStatus::Disputed, Some(amount)) if !amount.is_positive() => {
+                Err(Error::InvalidDisputeAmount(tx))?
+            }
+            (TxStatus::Active | TxStatus::Disputed, Some(amount)) if amount > remaining => {
+                Err(Error::DisputeAmountExceeded(tx))?
+            }
+            (TxStatus::Active | TxStatus::Disputed, Some(amount)) => amount,
+            (status, _) => Err(Error::UnexpectedTxStatus(status))?,
+        };
+
+        self.disputed = self
+            .disputed
+            .checked_add(amount)
+            .ok_or(balance::Error::Overflow)?;
+        self.open = self
+            .open
+            .checked_add(amount)
+            .ok_or(balance::Error::Overflow)?;
         self.status = TxStatus::Disputed;
 
-        Ok(())
+        Ok(amount)
+    }
+
+    /// Release `amount` of the open dispute, or all of it. Returns the amount released
+    /// and whether the dispute is now closed
+    fn resolve(&mut self, amount: Option<Amount>) -> Result<(Amount, bool), Error> {
+        self.settle(amount, false)
     }
 
-    fn resolve(&mut self) -> Result<(), Error> {
+    /// Charge back `amount` of the open dispute, or all of it. Returns the amount charged
+    /// back and whether the dispute is now closed
+    fn charge_back(&mut self, amount: Option<Amount>) -> Result<(Amount, bool), Error> {
+        self.settle(amount, true)
+    }
+
+    fn settle(
+        &mut self,
+        amount: Option<Amount>,
+        charge_back: bool,
+    ) -> Result<(Amount, bool), Error> {
         if self.status != TxStatus::Disputed {
             Err(Error::UnexpectedTxStatus(self.status))?
         }
 
-        self.status = TxStatus::Resolved;
+        let tx = *self.t.tx();
+        let amount = match amount {
+            None => self.open,
+            Some(amount) if !amount.is_positive() => Err(Error::InvalidDisputeAmount(tx))?,
+            Some(amount) if amount > self.open => Err(Error::DisputeAmountExceeded(tx))?,
+            Some(amount) => amount,
+        };
+
+        self.open = self
+            .open
+            .checked_sub(amount)
+            .ok_or(balance::Error::Overflow)?;
+        if charge_back {
+            self.charged_back = self
+                .charged_back
+                .checked_add(amount)
+                .ok_or(balance::Error::Overflow)?;
+        }
 
-        Ok(())
+        // Once nothing is left in dispute the entry is settled, unless only part of it was
+        // ever disputed. The rest may still be disputed later
+        let closed = self.open == Amount::ZERO;
+        if closed {
+            self.status = match self.t.amount() {
+                Some(amount) if self.disputed < amount => TxStatus::Active,
+                _ if self.charged_back.is_positive() => TxStatus::ChargedBack,
+                _ => TxStatus::Resolved,
+            };
+        }
+
+        Ok((amount, closed))
     }
 
-    fn charge_back(&mut self) -> Result<(), Error> {
-        if self.status != TxStatus::Disputed {
+    /// Reverse the charge back on the transaction, returning the amount which was charged
+    /// back. Part of an entry may have been charged back while the rest was left alone, in
+    /// which case the entry stays as it is with nothing charged back
+    fn represent(&mut self) -> Result<Amount, Error> {
+        if self.status == TxStatus::Represented || !self.charged_back.is_positive() {
             Err(Error::UnexpectedTxStatus(self.status))?
         }
 
-        self.status = TxStatus::ChargedBack;
+        let charged_back = self.charged_back;
+        match self.status {
+            TxStatus::ChargedBack => self.status = TxStatus::Represented,
+            _ => self.charged_back = Amount::ZERO,
+        }
 
-        Ok(())
+        Ok(charged_back)
+    }
+
+    /// The currency holds on the entry are placed in. An entry without one, such as an
+    /// unlock, has nothing to hold and can't be disputed whatever the policy says
+    fn currency(&self) -> Result<Currency, Error> {
+        self.t
+            .currency()
+            .ok_or_else(|| Error::NotDisputable(*self.t.tx()))
+    }
+
+    /// Whether any of the transaction stands charged back
+    fn stands_charged_back(&self) -> bool {
+        self.status != TxStatus::Represented && self.charged_back.is_positive()
+    }
+
+    /// How much of the transaction has been charged back
+    pub fn charged_back(&self) -> Amount {
+        self.charged_back
     }
 }
 
-/// Each user will have a ledger of transactions. This will aim at being compact
-/// But perhaps expensive at large numbers of transactions for now
+/// Each user will have a ledger of transactions. Balances are always held in memory, while
+/// the entries behind them are kept wherever the [`TransactionStore`] keeps them
 pub struct Ledger {
-    /// Mapping of a transaction by id to the index it's written into memory
-    client_tx_to_idx: HashMap<(Client, Tx), usize>,
+    /// Balances for each client in each currency they've transacted in
+    balance: HashMap<(Client, Currency), Balance>,
+
+    /// Clients which have been frozen. This covers every currency the client holds
+    locked: HashSet<Client>,
+
+    /// All entries within this ledger
+    transactions: Box<dyn TransactionStore>,
+
+    /// Rules for what a dispute does
+    policy: Arc<dyn DisputePolicy>,
+
+    /// How long entries may be disputed for and how long a dispute may stay open
+    window: DisputeWindow,
+
+    /// How far each client may overdraw
+    limits: CreditLimits,
+
+    /// Which transactions are charged a fee
+    fees: FeeSchedule,
+
+    /// The id given to each client's most recent fee
+    fee_txs: HashMap<Client, Tx>,
+
+    /// Double-entry postings of every change to a balance, when they're being kept
+    books: Option<Books>,
+
+    /// Whether reversing the last charge back on an account lifts its freeze
+    unlock_on_represent: bool,
+
+    /// How many of each client's entries stand charged back, at least in part
+    charge_backs: HashMap<Client, usize>,
+
+    /// Names of the inputs entries were read from, shared by every entry from each input
+    sources: HashSet<Arc<str>>,
+
+    /// The latest timestamp seen. Transactions without a timestamp happen at this time
+    clock: Option<Timestamp>,
 
-    /// Balances for each client
-    balance: HashMap<Client, Balance>,
+    /// Open disputes with a deadline, ordered by when they expire
+    deadlines: BTreeSet<(Timestamp, Client, Tx)>,
 
-    /// All transactions within this ledger
-    transactions: Vec<Entry>,
+    /// How the ledger stood before the transaction being applied, while one is
+    undo: Option<Undo>,
+}
+
+/// What a transaction has changed so far, kept so that one which fails part way through
+/// can be put back as though it was never applied. A transaction only changes the freeze,
+/// charge backs and fees of its own client, while it may change the balances of others and
+/// post to the books
+#[derive(Debug)]
+struct Undo {
+    client: Client,
+
+    /// Whether the client was frozen
+    locked: bool,
+
+    /// How many of the client's entries stood charged back
+    charge_backs: Option<usize>,
+
+    /// The id of the client's last fee
+    fee_tx: Option<Tx>,
+
+    /// How many postings the books held, when they're kept
+    postings: Option<usize>,
+
+    /// Each balance changed as it was beforehand, or `None` where it was opened
+    balances: Vec<((Client, Currency), Option<Balance>)>,
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Ledger {
     pub fn new() -> Self {
         Ledger {
-            client_tx_to_idx: HashMap::new(),
             balance: HashMap::new(),
-            transactions: Vec::new(),
+            locked: HashSet::new(),
+            transactions: Box::new(MemoryStore::default()),
+            policy: Arc::new(StandardPolicy),
+            window: DisputeWindow::default(),
+            limits: CreditLimits::default(),
+            fees: FeeSchedule::default(),
+            fee_txs: HashMap::new(),
+            books: None,
+            unlock_on_represent: false,
+            charge_backs: HashMap::new(),
+            sources: HashSet::new(),
+            clock: None,
+            deadlines: BTreeSet::new(),
+            undo: None,
+        }
+    }
+
+    /// Replace the [`StandardPolicy`] consulted whenever a transaction is disputed
+    pub fn with_dispute_policy(mut self, policy: impl DisputePolicy + 'static) -> Self {
+        self.policy = Arc::new(policy);
+        self
+    }
+
+    /// Keep entries in `store` rather than in memory. Entries already in the ledger are
+    /// moved across
+    pub fn with_transaction_store(
+        mut self,
+        store: impl TransactionStore + 'static,
+    ) -> Result<Self, store::Error> {
+        let mut store: Box<dyn TransactionStore> = Box::new(store);
+        for entry in self.transactions.entries() {
+            store.insert(entry?)?;
         }
+        self.transactions = store;
+        Ok(self)
     }
 
+    /// Apply a single transaction to the ledger, or return the reason it was rejected
     pub fn process_transaction(&mut self, t: Transaction) -> Result<(), Error> {
-        let key = t.key();
+        self.process_transaction_at(t, None)
+    }
+
+    /// Apply a transaction which happened at `at`. Any dispute whose deadline has passed
+    /// by then is settled first
+    pub fn process_transaction_at(
+        &mut self,
+        t: Transaction,
+        at: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        self.process_transaction_from(t, at, None)
+    }
+
+    /// Apply a transaction which happened at `at` and was read from `source`. The source
+    /// is kept with the entry the transaction registers
+    pub fn process_transaction_from(
+        &mut self,
+        t: Transaction,
+        at: Option<Timestamp>,
+        source: Option<&str>,
+    ) -> Result<(), Error> {
+        if let Some(at) = at {
+            self.advance_clock(at)?;
+        }
+        let now = at.or(self.clock);
+
+        let client = *t.client();
 
         // --- Check for Reasons not to Process ---
 
         // Ensure we're not looking at a duplicate transaction
-        if matches!(
-            &t,
-            &Transaction::Deposit { .. } | &Transaction::Withdrawal { .. }
-        ) && self.client_tx_to_idx.contains_key(&key)
-        {
+        if t.is_entry() && self.transactions.contains(&t.entry_key()) {
             Err(Error::DuplicateTransaction(*t.tx()))?
         }
 
-        // Return early if there is no balance for this client on non-deposit transactions
-        if !self.balance.contains_key(t.client()) {
-            self.balance.insert(*t.client(), Balance::new(*t.client()));
+        // If the client is locked this transaction will be ignored. Operators may still act on
+        // it, fees are still charged, and a charge back which froze it may be reversed
+        let exempt = matches!(
+            &t,
+            Transaction::Admin { .. } | Transaction::Fee { .. } | Transaction::Represent { .. }
+        );
+        if self.locked.contains(&client) && !exempt {
+            Err(Error::FrozenAccountError(client))?;
         }
 
         // --- Attempt to Process ---
-        let b = self.balance.get_mut(t.client()).expect("Initialized above");
 
-        // If the balance is locked this transaction will be ignored
-        if b.locked() {
-            Err(Error::FrozenAccountError(*t.client()))?;
-        }
+        self.atomically(client, |ledger| ledger.apply(t, now, source))
+    }
+
+    /// Apply a transaction which passed the checks made on every transaction. Entries are
+    /// written to the store last, once nothing else can fail
+    fn apply(
+        &mut self,
+        t: Transaction,
+        now: Option<Timestamp>,
+        source: Option<&str>,
+    ) -> Result<(), Error> {
+        let key = t.key();
+        let client = *t.client();
+
+        // The entry of the fee this transaction is charged, written along with its own
+        let mut fee = None;
 
         match &t {
-            Transaction::Deposit { amount, .. } => {
-                b.deposit(*amount)?;
+            Transaction::Deposit {
+                amount, currency, ..
+            } => {
+                self.balance_mut(client, *currency).deposit(*amount)?;
+                self.post(
+                    key,
+                    *currency,
+                    Account::Cash,
+                    Account::Client(client),
+                    *amount,
+                )?;
             }
-            Transaction::Withdrawal { amount, .. } => {
-                b.withdraw(*amount)?;
+            Transaction::Withdrawal {
+                amount, currency, ..
+            } => {
+                self.balance_mut(client, *currency).withdraw(*amount)?;
+                self.post(
+                    key,
+                    *currency,
+                    Account::Client(client),
+                    Account::Cash,
+                    *amount,
+                )?;
+                fee = self.charge_fee(
+                    FeeTrigger::Withdrawal,
+                    client,
+                    *amount,
+                    *currency,
+                    *t.tx(),
+                    now,
+                )?;
             }
-            Transaction::Dispute { .. } => {
-                if let Some(idx) = self.client_tx_to_idx.get(&key) {
-                    let entry = self
-                        .transactions
-                        .get_mut(*idx)
-                        .expect("idx tracks growing allocation");
-
-                    // This check should prevent the below hold from raising it's own error
-                    // As we enforce strict state transitions on the private status
-                    entry.dispute()?;
-
-                    if let &Transaction::Deposit { amount, .. } = &entry.t {
-                        b.hold(*t.tx(), amount)?
-                    } else if let &Transaction::Withdrawal { amount, .. } = &entry.t {
-                        b.hold(*t.tx(), -1f64 * amount)?
+            Transaction::Transfer {
+                to,
+                amount,
+                currency,
+                ..
+            } => self.transfer(key, *to, *amount, *currency)?,
+            Transaction::Dispute { amount, .. } => {
+                let policy = self.policy.clone();
+                let window = self.window;
+                let mut entry = self.entry(&key)?;
+
+                if !policy.is_disputable(&entry.t) {
+                    Err(Error::NotDisputable(*t.tx()))?
+                }
+
+                // Holds are placed in the currency of the original transaction
+                let currency = entry.currency()?;
+
+                if !window.accepts_dispute(entry.at, now) {
+                    Err(Error::DisputeWindowClosed(*t.tx()))?
+                }
+
+                // Later parts of a dispute add to the hold already placed by the first
+                let first = entry.status == TxStatus::Active;
+
+                // This check should prevent the below hold from raising it's own error
+                // As we enforce strict state transitions on the private status
+                let disputed = entry.dispute(*amount)?;
+                let amount = policy.hold_amount(&entry.t, disputed)?;
+                let holds = self.split_hold(&entry.t, amount)?;
+
+                // The deadline runs from the first part of the dispute
+                let deadline = match first {
+                    true => window.deadline(now),
+                    false => None,
+                };
+                if first {
+                    entry.deadline = deadline;
+                }
+
+                for (holder, amount) in holds {
+                    let balance = self.balance_mut(holder, currency);
+                    match first {
+                        true => balance.hold(*t.tx(), amount)?,
+                        false => balance.extend_hold(*t.tx(), amount)?,
+                    }
+
+                    // Only positive holds take money out of what's available, see `Balance::held`
+                    if amount.is_positive() {
+                        self.post(
+                            key,
+                            currency,
+                            Account::Client(holder),
+                            Account::HeldFunds,
+                            amount,
+                        )?;
                     }
-                } else {
-                    Err(Error::MissingTransaction(*t.tx()))?;
                 }
-            }
-            Transaction::Resolve { .. } => {
-                if let Some(idx) = self.client_tx_to_idx.get(&key) {
-                    let entry = self
-                        .transactions
-                        .get_mut(*idx)
-                        .expect("idx tracks growing allocation");
-
-                    // This ensures that this transaction was in the "disputed" state and forces it forward to resolved
-                    entry.resolve()?;
-
-                    // Remove the hold from this entry on the balance.
-                    b.remove_hold(*t.tx())?;
-                } else {
-                    Err(Error::MissingTransaction(*t.tx()))?;
+                let fee = match entry.t.amount() {
+                    Some(_) => self.charge_fee(
+                        FeeTrigger::Dispute,
+                        client,
+                        disputed,
+                        currency,
+                        *t.tx(),
+                        now,
+                    )?,
+                    None => None,
+                };
+
+                self.transactions
+                    .write(Some(&entry), fee.into_iter().collect())?;
+                if let Some(deadline) = deadline {
+                    self.deadlines.insert((deadline, client, *t.tx()));
                 }
             }
-            Transaction::ChargeBack { .. } => {
-                if let Some(idx) = self.client_tx_to_idx.get(&key) {
-                    let entry = self
-                        .transactions
-                        .get_mut(*idx)
-                        .expect("idx tracks growing allocation");
-
-                    // This ensures that this transaction was in the "disputed" state and forces it forward to resolved
-                    entry.charge_back()?;
-
-                    // Remove the hold from this entry on the balance.
-                    b.apply_hold(*t.tx())?;
-                    b.lock_balance();
-                } else {
-                    Err(Error::MissingTransaction(*t.tx()))?;
+            Transaction::Resolve { amount, .. } => self.resolve_dispute(&key, *amount)?,
+            Transaction::ChargeBack { amount, .. } => self.charge_back_dispute(&key, *amount)?,
+            Transaction::Represent { .. } => self.represent(&key)?,
+            Transaction::Admin { action, .. } => match action {
+                AdminAction::Unlock => {
+                    if !self.locked.contains(&client) {
+                        Err(Error::AccountNotFrozen(client))?
+                    }
+                    self.unlock_client(client);
                 }
+                AdminAction::Freeze => self.lock_client(client),
+                AdminAction::Credit { amount, currency } => {
+                    self.balance_mut(client, *currency).credit(*amount)?;
+                    let (debit, credit) = (Account::Adjustments, Account::Client(client));
+                    self.post(key, *currency, debit, credit, *amount)?;
+                }
+                AdminAction::Debit { amount, currency } => {
+                    self.balance_mut(client, *currency).debit(*amount)?;
+                    let (debit, credit) = (Account::Client(client), Account::Adjustments);
+                    self.post(key, *currency, debit, credit, *amount)?;
+                }
+            },
+            Transaction::Fee {
+                amount, currency, ..
+            } => {
+                self.balance_mut(client, *currency).charge_fee(*amount)?;
+                let (debit, credit) = (Account::Client(client), Account::FeeIncome);
+                self.post(key, *currency, debit, credit, *amount)?;
             }
         }
 
-        // --- Register deposits and withdrawals ---
+        // --- Register entries ---
 
-        if matches!(
-            &t,
-            &Transaction::Deposit { .. } | &Transaction::Withdrawal { .. }
-        ) {
-            // Get new index for this transaction
-            let index = self.transactions.len();
-            self.client_tx_to_idx.insert(key, index);
+        if t.is_entry() {
+            let mut entry = Entry::new(t, now);
+            entry.source = source.map(|source| self.intern_source(source));
+            self.transactions
+                .write(None, [entry].into_iter().chain(fee).collect())?;
+        }
+
+        Ok(())
+    }
+
+    /// Make a change to the ledger on behalf of `client` which happens in full or not at
+    /// all. If `change` fails everything it did to the ledger in memory is put back, so it
+    /// must only write to the store once nothing else can fail
+    fn atomically(
+        &mut self,
+        client: Client,
+        change: impl FnOnce(&mut Self) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        // Already within a change which will be put back as a whole
+        if self.undo.is_some() {
+            return change(self);
+        }
+
+        self.undo = Some(Undo {
+            client,
+            locked: self.locked.contains(&client),
+            charge_backs: self.charge_backs.get(&client).copied(),
+            fee_tx: self.fee_txs.get(&client).copied(),
+            postings: self.books.as_ref().map(|books| books.postings().len()),
+            balances: Vec::new(),
+        });
+        let changed = change(self);
+        let undo = self.undo.take().expect("Set above");
+
+        if changed.is_err() {
+            for (key, balance) in undo.balances.into_iter().rev() {
+                match balance {
+                    Some(balance) => self.balance.insert(key, balance),
+                    None => self.balance.remove(&key),
+                };
+            }
+
+            match undo.locked {
+                true if !self.locked.contains(&undo.client) => self.lock_client(undo.client),
+                false if self.locked.contains(&undo.client) => self.unlock_client(undo.client),
+                _ => {}
+            }
+
+            match undo.charge_backs {
+                Some(count) => self.charge_backs.insert(undo.client, count),
+                None => self.charge_backs.remove(&undo.client),
+            };
+
+            match undo.fee_tx {
+                Some(tx) => self.fee_txs.insert(undo.client, tx),
+                None => self.fee_txs.remove(&undo.client),
+            };
+
+            if let (Some(books), Some(len)) = (self.books.as_mut(), undo.postings) {
+                books.truncate(len);
+            }
+        }
+
+        changed
+    }
+
+    /// Settle the dispute on an entry in the client's favour
+    fn resolve_dispute(&mut self, key: &(Client, Tx), amount: Option<Amount>) -> Result<(), Error> {
+        let policy = self.policy.clone();
+        let mut entry = self.entry(key)?;
+
+        // This ensures that this transaction was in the "disputed" state and forces it forward to resolved
+        let (resolved, closed) = entry.resolve(amount)?;
+        let currency = entry.currency()?;
+
+        // Part of a dispute releases its own share of the hold
+        let part = match closed {
+            true => Amount::ZERO,
+            false => policy.hold_amount(&entry.t, resolved)?,
+        };
+        let holds = self.split_hold(&entry.t, part)?;
+
+        // The deadline goes once nothing is left in dispute
+        let deadline = match closed {
+            true => entry.deadline.take(),
+            false => None,
+        };
+
+        // Remove the hold from this entry on each balance it was placed on
+        for (holder, part) in holds {
+            let balance = self.balance_mut(holder, currency);
+            let held = match closed {
+                false => balance.release_part_of_hold(key.1, part).map(|_| part)?,
+                true => balance.remove_hold(key.1)?,
+            };
+            if held.is_positive() {
+                self.post(
+                    *key,
+                    currency,
+                    Account::HeldFunds,
+                    Account::Client(holder),
+                    held,
+                )?;
+            }
+        }
 
-            // Add the transaction as an entry
-            let entry = Entry::new(t);
-            self.transactions.push(entry);
+        self.transactions.update(&entry)?;
+        if let Some(deadline) = deadline {
+            self.deadlines.remove(&(deadline, key.0, key.1));
         }
 
         Ok(())
     }
 
+    /// Settle the dispute on an entry against the client
+    fn charge_back_dispute(
+        &mut self,
+        key: &(Client, Tx),
+        amount: Option<Amount>,
+    ) -> Result<(), Error> {
+        let policy = self.policy.clone();
+        let mut entry = self.entry(key)?;
+
+        // This ensures that this transaction was in the "disputed" state and forces it forward to resolved
+        let first = !entry.stands_charged_back();
+        let (charged_back, closed) = entry.charge_back(amount)?;
+        let currency = entry.currency()?;
+
+        let effect = policy.charge_back_effect(&entry.t);
+
+        // Part of a dispute applies its own share of the hold
+        let part = match closed {
+            true => Amount::ZERO,
+            false => policy.hold_amount(&entry.t, charged_back)?,
+        };
+        let holds = self.split_hold(&entry.t, part)?;
+
+        // The deadline goes once nothing is left in dispute
+        let deadline = match closed {
+            true => entry.deadline.take(),
+            false => None,
+        };
+
+        // Remove the hold from this entry on each balance. Held funds are paid back out,
+        // while a negative hold returns money to the client at the business' expense
+        for (holder, part) in holds {
+            let balance = self.balance_mut(holder, currency);
+            let held = match closed {
+                false => balance.apply_part_of_hold(key.1, part).map(|_| part)?,
+                true => balance.apply_hold(key.1)?,
+            };
+            if held.is_positive() {
+                self.post(*key, currency, Account::HeldFunds, Account::Cash, held)?;
+            } else if let Some(returned) = held.checked_neg().filter(|a| a.is_positive()) {
+                let (debit, credit) = (Account::ChargeBackLosses, Account::Client(holder));
+                self.post(*key, currency, debit, credit, returned)?;
+            }
+        }
+        if first {
+            *self.charge_backs.entry(key.0).or_default() += 1;
+        }
+        if effect == ChargeBackEffect::LockAccount {
+            self.lock_client(key.0);
+        }
+
+        let now = self.clock;
+        let fee = match entry.t.amount() {
+            Some(_) => self.charge_fee(
+                FeeTrigger::ChargeBack,
+                key.0,
+                charged_back,
+                currency,
+                key.1,
+                now,
+            )?,
+            None => None,
+        };
+
+        self.transactions
+            .write(Some(&entry), fee.into_iter().collect())?;
+        if let Some(deadline) = deadline {
+            self.deadlines.remove(&(deadline, key.0, key.1));
+        }
+
+        Ok(())
+    }
+
+    /// Find the registered entry a dispute, resolve or charge back refers to. Changes
+    /// to it are written back with [`TransactionStore::update`]
+    fn entry(&self, key: &(Client, Tx)) -> Result<Entry, Error> {
+        self.transactions
+            .get(&EntryKey::Transaction(key.0, key.1))?
+            .ok_or(Error::MissingTransaction(key.1))
+    }
+
+    /// The shared name of an input, registering it if this is the first time it's seen
+    fn intern_source(&mut self, source: &str) -> Arc<str> {
+        if let Some(source) = self.sources.get(source) {
+            return source.clone();
+        }
+        let source: Arc<str> = Arc::from(source);
+        self.sources.insert(source.clone());
+        source
+    }
+
+    /// Get the balance of a client in a currency, opening it if this is the first time it's seen
+    fn balance_mut(&mut self, client: Client, currency: Currency) -> &mut Balance {
+        let key = (client, currency);
+        if let Some(undo) = self.undo.as_mut()
+            && !undo.balances.iter().any(|(changed, _)| *changed == key)
+        {
+            undo.balances.push((key, self.balance.get(&key).cloned()));
+        }
+
+        let locked = self.locked.contains(&client);
+        let limit = self.limits.get(client, currency);
+        self.balance.entry(key).or_insert_with(|| {
+            let mut b = Balance::new(client, currency);
+            b.set_credit_limit(limit);
+            // Balances opened on a frozen account by an operator are frozen too
+            if locked {
+                b.lock_balance();
+            }
+            b
+        })
+    }
+
+    /// Freeze every balance held by the client
+    fn lock_client(&mut self, client: Client) {
+        self.locked.insert(client);
+
+        for ((c, _), b) in self.balance.iter_mut() {
+            if *c == client {
+                b.lock_balance();
+            }
+        }
+    }
+
+    /// Lift the freeze from every balance held by the client
+    fn unlock_client(&mut self, client: Client) {
+        self.locked.remove(&client);
+
+        for ((c, _), b) in self.balance.iter_mut() {
+            if *c == client {
+                b.unlock_balance();
+            }
+        }
+    }
+
     /// Get the balance of a client in the ledger. If the client has been registered
     /// There will be a Some(balance) returned
     #[cfg(test)]
-    pub fn get_available_balance(&self, client: Client) -> Option<f64> {
-        match self.balance.get(&client) {
-            Some(b) => Some(b.available()),
-            None => None,
-        }
+    pub fn get_available_balance(&self, client: Client) -> Option<Amount> {
+        self.balance
+            .get(&(client, Currency::NONE))
+            .map(|b| b.available())
     }
 
-    /// For all of the registered clients within the ledger take a snapshot of their balance and return it in a vector
+    /// For all of the registered clients within the ledger take a snapshot of their balance and return it in a vector.
+    /// There is one snapshot for every currency a client holds, ordered by client then currency
     pub fn get_client_snapshots(&self) -> Vec<BalanceSnapshot> {
-        self.balance.values().map(|b| b.snapshot()).collect()
+        let mut snapshots: Vec<BalanceSnapshot> =
+            self.balance.values().map(|b| b.snapshot()).collect();
+        snapshots.sort_by_key(|s| (s.client, s.currency));
+        snapshots
+    }
+
+    /// Take a snapshot of a single client's balance in every currency they hold, ordered by currency
+    pub fn get_client_snapshot(&self, client: Client) -> Vec<BalanceSnapshot> {
+        let mut snapshots: Vec<BalanceSnapshot> = self
+            .balance
+            .iter()
+            .filter(|((c, _), _)| *c == client)
+            .map(|(_, b)| b.snapshot())
+            .collect();
+        snapshots.sort_by_key(|s| s.currency);
+        snapshots
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::{
+        io,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+    };
+
     use anyhow::Result;
 
-    use crate::ledger::{Ledger, Transaction};
+    use crate::ledger::{
+        AdminAction, Entry, EntryKey, Error, Ledger, Transaction, Tx, TxStatus,
+        amount::Amount,
+        currency::Currency,
+        fees::{FeeRule, FeeSchedule, FeeTrigger},
+        store::{self, Entries, MemoryStore, TransactionStore},
+    };
+
+    fn amount(s: &str) -> Amount {
+        s.parse().expect("valid amount literal")
+    }
+
+    /// Keeps entries in memory, but fails every write while `failing` is set
+    #[derive(Default)]
+    struct FailingStore {
+        inner: MemoryStore,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl FailingStore {
+        fn check(&self) -> Result<(), store::Error> {
+            match self.failing.load(Ordering::SeqCst) {
+                true => Err(io::Error::other("store unavailable").into()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl TransactionStore for FailingStore {
+        fn contains(&self, key: &EntryKey) -> bool {
+            self.inner.contains(key)
+        }
+
+        fn get(&self, key: &EntryKey) -> Result<Option<Entry>, store::Error> {
+            self.inner.get(key)
+        }
+
+        fn insert(&mut self, entry: Entry) -> Result<(), store::Error> {
+            self.check()?;
+            self.inner.insert(entry)
+        }
+
+        fn update(&mut self, entry: &Entry) -> Result<(), store::Error> {
+            self.check()?;
+            self.inner.update(entry)
+        }
+
+        fn write(
+            &mut self,
+            updated: Option<&Entry>,
+            inserted: Vec<Entry>,
+        ) -> Result<(), store::Error> {
+            self.check()?;
+            self.inner.write(updated, inserted)
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+
+        fn entries(&self) -> Entries<'_> {
+            self.inner.entries()
+        }
+
+        fn empty(&self, part: Option<usize>) -> Result<Box<dyn TransactionStore>, store::Error> {
+            self.inner.empty(part)
+        }
+    }
 
     #[test]
     fn process_first_deposit() -> Result<()> {
         let t = Transaction::Deposit {
             client: 0,
             tx: 1,
-            amount: 100f64,
+            amount: amount("100"),
+            currency: Currency::NONE,
         };
         let mut ledger = Ledger::new();
 
         // Should succeed
         ledger.process_transaction(t)?;
 
-        assert_eq!(100f64, ledger.get_available_balance(0).unwrap());
+        assert_eq!(amount("100"), ledger.get_available_balance(0).unwrap());
 
         Ok(())
     }
@@ -320,16 +1267,92 @@ mod test {
         let t1 = Transaction::Deposit {
             client: 0,
             tx: 1,
-            amount: 100f64,
+            amount: amount("100"),
+            currency: Currency::NONE,
+        };
+        let t2 = Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
         };
-        let t2 = Transaction::Dispute { client: 0, tx: 1 };
 
         let mut ledger = Ledger::new();
 
         ledger.process_transaction(t1)?;
         ledger.process_transaction(t2)?;
 
-        assert_eq!(0f64, ledger.get_available_balance(0).unwrap());
+        assert_eq!(amount("0"), ledger.get_available_balance(0).unwrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn partial_disputes() -> Result<()> {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: amount("100"),
+            currency: Currency::NONE,
+        })?;
+        let dispute = |value: &str| Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: Some(amount(value)),
+        };
+
+        // Each part of the dispute adds to the hold, up to the amount of the deposit
+        ledger.process_transaction(dispute("20"))?;
+        ledger.process_transaction(dispute("30"))?;
+        assert_eq!(amount("50"), ledger.get_available_balance(0).unwrap());
+        assert!(matches!(
+            ledger.process_transaction(dispute("50.0001")),
+            Err(Error::DisputeAmountExceeded(1))
+        ));
+        assert!(matches!(
+            ledger.process_transaction(dispute("-1")),
+            Err(Error::InvalidDisputeAmount(1))
+        ));
+
+        // Settling the whole of what's open leaves the rest of the deposit disputable
+        ledger.process_transaction(Transaction::Resolve {
+            client: 0,
+            tx: 1,
+            amount: Some(amount("10")),
+        })?;
+        assert_eq!(amount("60"), ledger.get_available_balance(0).unwrap());
+        ledger.process_transaction(Transaction::Resolve {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        assert_eq!(amount("100"), ledger.get_available_balance(0).unwrap());
+        let entry = ledger.get_entry(0, 1)?.expect("deposit was registered");
+        assert_eq!(TxStatus::Active, entry.status());
+        assert_eq!(amount("50"), entry.disputed());
+
+        // Without an amount the dispute takes everything not yet disputed
+        ledger.process_transaction(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        assert_eq!(amount("50"), ledger.get_available_balance(0).unwrap());
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        let snapshot = ledger.get_client_snapshot(0)[0];
+        assert_eq!(amount("50"), snapshot.total);
+        assert!(snapshot.locked);
+        assert_eq!(
+            TxStatus::ChargedBack,
+            ledger
+                .get_entry(0, 1)?
+                .expect("deposit was registered")
+                .status()
+        );
 
         Ok(())
     }
@@ -339,14 +1362,20 @@ mod test {
         let t1 = Transaction::Deposit {
             client: 0,
             tx: 1,
-            amount: 100f64,
+            amount: amount("100"),
+            currency: Currency::NONE,
         };
         let t2 = Transaction::Withdrawal {
             client: 0,
             tx: 2,
-            amount: 10f64,
+            amount: amount("10"),
+            currency: Currency::NONE,
+        };
+        let t3 = Transaction::Dispute {
+            client: 0,
+            tx: 2,
+            amount: None,
         };
-        let t3 = Transaction::Dispute { client: 0, tx: 2 };
 
         let mut ledger = Ledger::new();
 
@@ -354,7 +1383,7 @@ mod test {
         ledger.process_transaction(t2)?;
         ledger.process_transaction(t3)?;
 
-        assert_eq!(90f64, ledger.get_available_balance(0).unwrap());
+        assert_eq!(amount("90"), ledger.get_available_balance(0).unwrap());
 
         Ok(())
     }
@@ -364,15 +1393,25 @@ mod test {
         let t1 = Transaction::Deposit {
             client: 0,
             tx: 1,
-            amount: 100f64,
+            amount: amount("100"),
+            currency: Currency::NONE,
         };
         let t2 = Transaction::Withdrawal {
             client: 0,
             tx: 2,
-            amount: 10f64,
+            amount: amount("10"),
+            currency: Currency::NONE,
+        };
+        let t3 = Transaction::Dispute {
+            client: 0,
+            tx: 2,
+            amount: None,
+        };
+        let t4 = Transaction::Resolve {
+            client: 0,
+            tx: 2,
+            amount: None,
         };
-        let t3 = Transaction::Dispute { client: 0, tx: 2 };
-        let t4 = Transaction::Resolve { client: 0, tx: 2 };
 
         let mut ledger = Ledger::new();
 
@@ -381,7 +1420,7 @@ mod test {
         ledger.process_transaction(t3)?;
         ledger.process_transaction(t4)?;
 
-        assert_eq!(90f64, ledger.get_available_balance(0).unwrap());
+        assert_eq!(amount("90"), ledger.get_available_balance(0).unwrap());
 
         Ok(())
     }
@@ -391,15 +1430,25 @@ mod test {
         let t1 = Transaction::Deposit {
             client: 0,
             tx: 1,
-            amount: 100f64,
+            amount: amount("100"),
+            currency: Currency::NONE,
+        };
+        let t2 = Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
         };
-        let t2 = Transaction::Dispute { client: 0, tx: 1 };
         let t3 = Transaction::Deposit {
             client: 0,
             tx: 2,
-            amount: 50f64,
+            amount: amount("50"),
+            currency: Currency::NONE,
+        };
+        let t4 = Transaction::ChargeBack {
+            client: 0,
+            tx: 1,
+            amount: None,
         };
-        let t4 = Transaction::ChargeBack { client: 0, tx: 1 };
 
         let mut ledger = Ledger::new();
 
@@ -408,7 +1457,7 @@ mod test {
         ledger.process_transaction(t3)?;
         ledger.process_transaction(t4)?;
 
-        assert_eq!(50f64, ledger.get_available_balance(0).unwrap());
+        assert_eq!(amount("50"), ledger.get_available_balance(0).unwrap());
 
         // At this point no further actions should succeed
         assert!(
@@ -416,7 +1465,8 @@ mod test {
                 .process_transaction(Transaction::Deposit {
                     client: 0,
                     tx: 3,
-                    amount: 10f64
+                    amount: amount("10"),
+                    currency: Currency::NONE
                 })
                 .is_err()
         );
@@ -425,27 +1475,40 @@ mod test {
                 .process_transaction(Transaction::Withdrawal {
                     client: 0,
                     tx: 4,
-                    amount: 40f64
+                    amount: amount("40"),
+                    currency: Currency::NONE
                 })
                 .is_err()
         );
         assert!(
             ledger
-                .process_transaction(Transaction::Dispute { client: 0, tx: 2 })
+                .process_transaction(Transaction::Dispute {
+                    client: 0,
+                    tx: 2,
+                    amount: None,
+                })
                 .is_err()
         );
         assert!(
             ledger
-                .process_transaction(Transaction::Resolve { client: 0, tx: 2 })
+                .process_transaction(Transaction::Resolve {
+                    client: 0,
+                    tx: 2,
+                    amount: None,
+                })
                 .is_err()
         );
         assert!(
             ledger
-                .process_transaction(Transaction::ChargeBack { client: 0, tx: 2 })
+                .process_transaction(Transaction::ChargeBack {
+                    client: 0,
+                    tx: 2,
+                    amount: None,
+                })
                 .is_err()
         );
 
-        assert_eq!(50f64, ledger.get_available_balance(0).unwrap());
+        assert_eq!(amount("50"), ledger.get_available_balance(0).unwrap());
 
         Ok(())
     }
@@ -455,15 +1518,25 @@ mod test {
         let t1 = Transaction::Deposit {
             client: 0,
             tx: 1,
-            amount: 100f64,
+            amount: amount("100"),
+            currency: Currency::NONE,
         };
         let t2 = Transaction::Withdrawal {
             client: 0,
             tx: 2,
-            amount: 50f64,
+            amount: amount("50"),
+            currency: Currency::NONE,
+        };
+        let t3 = Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
+        };
+        let t4 = Transaction::Dispute {
+            client: 0,
+            tx: 2,
+            amount: None,
         };
-        let t3 = Transaction::Dispute { client: 0, tx: 1 };
-        let t4 = Transaction::Dispute { client: 0, tx: 2 };
 
         let mut ledger = Ledger::new();
         ledger.process_transaction(t1)?;
@@ -476,16 +1549,335 @@ mod test {
 
         let snapshot = snapshots[0];
         // 100 in 50 out - Both disputed. Total should be 50 as the "resolved" balance
-        assert_eq!(50f64, snapshot.total);
+        assert_eq!(amount("50"), snapshot.total);
         // As these are two separate transactions the total number of frozen dollars should be 150
-        assert_eq!(100f64, snapshot.held);
+        assert_eq!(amount("100"), snapshot.held);
         // As all transactions are disputed there shouldn't be any money available
-        assert_eq!(-50f64, snapshot.available);
+        assert_eq!(amount("-50"), snapshot.available);
         // Neither transaction is resolved or charged back
-        assert_eq!(false, snapshot.locked);
+        assert!(!snapshot.locked);
         // Sanity check
         assert_eq!(0, snapshot.client);
 
         Ok(())
     }
+
+    #[test]
+    fn balances_are_kept_per_currency() -> Result<()> {
+        let eur: Currency = "EUR".parse()?;
+        let usd: Currency = "USD".parse()?;
+
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: amount("100"),
+            currency: eur,
+        })?;
+        ledger.process_transaction(Transaction::Deposit {
+            client: 0,
+            tx: 2,
+            amount: amount("20"),
+            currency: usd,
+        })?;
+
+        // Can't withdraw more dollars than were deposited even though there are enough euros
+        assert!(
+            ledger
+                .process_transaction(Transaction::Withdrawal {
+                    client: 0,
+                    tx: 3,
+                    amount: amount("50"),
+                    currency: usd,
+                })
+                .is_err()
+        );
+
+        // The dispute holds euros as that's the currency of the original deposit
+        ledger.process_transaction(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+
+        let mut snapshots = ledger.get_client_snapshots();
+        snapshots.sort_by_key(|s| s.currency);
+        assert_eq!(2, snapshots.len());
+
+        assert_eq!(eur, snapshots[0].currency);
+        assert_eq!(amount("100"), snapshots[0].held);
+        assert_eq!(amount("0"), snapshots[0].available);
+
+        assert_eq!(usd, snapshots[1].currency);
+        assert_eq!(amount("0"), snapshots[1].held);
+        assert_eq!(amount("20"), snapshots[1].available);
+
+        // A charge back in one currency freezes the client in every currency
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        assert!(
+            ledger
+                .get_client_snapshots()
+                .iter()
+                .all(|snapshot| snapshot.locked)
+        );
+        assert!(
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client: 0,
+                    tx: 4,
+                    amount: amount("1"),
+                    currency: "GBP".parse()?,
+                })
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    fn admin(tx: u32, action: AdminAction) -> Transaction {
+        Transaction::Admin {
+            client: 0,
+            tx,
+            action,
+            operator: 7,
+            reason: "ticket 1234".to_string(),
+        }
+    }
+
+    #[test]
+    fn operator_unlocks_and_adjusts_frozen_account() -> Result<()> {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: amount("100"),
+            currency: Currency::NONE,
+        })?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+
+        // Adjustments apply to the frozen account
+        ledger.process_transaction(admin(
+            2,
+            AdminAction::Credit {
+                amount: amount("30"),
+                currency: Currency::NONE,
+            },
+        ))?;
+        assert_eq!(Some(amount("30")), ledger.get_available_balance(0));
+
+        // Admin entries share the transaction id space and can't be disputed
+        assert!(matches!(
+            ledger.process_transaction(admin(2, AdminAction::Freeze)),
+            Err(Error::DuplicateTransaction(2))
+        ));
+
+        ledger.process_transaction(admin(3, AdminAction::Unlock))?;
+        assert!(!ledger.get_client_snapshots()[0].locked);
+        assert!(matches!(
+            ledger.process_transaction(Transaction::Dispute {
+                client: 0,
+                tx: 2,
+                amount: None,
+            }),
+            Err(Error::NotDisputable(2))
+        ));
+
+        // The client may transact again
+        ledger.process_transaction(Transaction::Withdrawal {
+            client: 0,
+            tx: 4,
+            amount: amount("10"),
+            currency: Currency::NONE,
+        })?;
+        assert!(matches!(
+            ledger.process_transaction(admin(5, AdminAction::Unlock)),
+            Err(Error::AccountNotFrozen(0))
+        ));
+
+        ledger.process_transaction(admin(
+            6,
+            AdminAction::Debit {
+                amount: amount("25"),
+                currency: Currency::NONE,
+            },
+        ))?;
+        assert_eq!(Some(amount("-5")), ledger.get_available_balance(0));
+
+        ledger.process_transaction(admin(7, AdminAction::Freeze))?;
+        assert!(
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client: 0,
+                    tx: 8,
+                    amount: amount("1"),
+                    currency: Currency::NONE,
+                })
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn store_failures_leave_no_trace() -> Result<()> {
+        let store = FailingStore::default();
+        let failing = store.failing.clone();
+        let mut ledger = Ledger::new().with_transaction_store(store)?;
+        let deposit = |tx| Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: amount("100"),
+            currency: Currency::NONE,
+        };
+        let dispute = Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+        let charge_back = Transaction::ChargeBack {
+            client: 1,
+            tx: 1,
+            amount: None,
+        };
+
+        // Nothing is applied while the store can't be written
+        ledger.process_transaction(deposit(1))?;
+        failing.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            ledger.process_transaction(deposit(2)),
+            Err(Error::StoreError(_))
+        ));
+        assert!(matches!(
+            ledger.process_transaction(dispute.clone()),
+            Err(Error::StoreError(_))
+        ));
+        let snapshot = ledger.get_client_snapshot(1)[0];
+        assert_eq!(
+            (amount("100"), Amount::ZERO),
+            (snapshot.total, snapshot.held)
+        );
+
+        failing.store(false, Ordering::SeqCst);
+        ledger.process_transaction(dispute)?;
+        failing.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            ledger.process_transaction(charge_back.clone()),
+            Err(Error::StoreError(_))
+        ));
+        let snapshot = ledger.get_client_snapshot(1)[0];
+        assert_eq!(
+            (amount("100"), amount("100")),
+            (snapshot.total, snapshot.held)
+        );
+        assert!(!snapshot.locked);
+
+        // Once the store is back the same transactions go through
+        failing.store(false, Ordering::SeqCst);
+        ledger.process_transaction(charge_back)?;
+        ledger.process_transaction(Transaction::Admin {
+            client: 1,
+            tx: 3,
+            action: AdminAction::Unlock,
+            operator: 1,
+            reason: "appeal".to_string(),
+        })?;
+        ledger.process_transaction(deposit(2))?;
+        let snapshot = ledger.get_client_snapshot(1)[0];
+        assert_eq!(
+            (amount("100"), Amount::ZERO),
+            (snapshot.total, snapshot.held)
+        );
+        assert_eq!(
+            Some(TxStatus::ChargedBack),
+            ledger.get_entry(1, 1)?.map(|e| e.status())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn fees_are_written_with_their_transaction() -> Result<()> {
+        let mut fees = FeeSchedule::new();
+        fees.push(FeeRule {
+            on: FeeTrigger::Withdrawal,
+            min: Amount::ZERO,
+            max: None,
+            currency: None,
+            fee: amount("1"),
+        });
+        let store = FailingStore::default();
+        let failing = store.failing.clone();
+        let mut ledger = Ledger::new()
+            .with_fee_schedule(fees)
+            .with_transaction_store(store)?;
+        ledger.process_transaction(Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: amount("10"),
+            currency: Currency::NONE,
+        })?;
+        let withdrawal = Transaction::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: amount("5"),
+            currency: Currency::NONE,
+        };
+
+        // Neither the withdrawal nor its fee is charged while the store can't be written
+        failing.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            ledger.process_transaction(withdrawal.clone()),
+            Err(Error::StoreError(_))
+        ));
+        let snapshot = ledger.get_client_snapshot(1)[0];
+        assert_eq!(
+            (amount("10"), Amount::ZERO),
+            (snapshot.total, snapshot.fees)
+        );
+
+        // The fee takes the id it would have had all along
+        failing.store(false, Ordering::SeqCst);
+        ledger.process_transaction(withdrawal)?;
+        let history = ledger.get_client_history(1, 0, usize::MAX)?;
+        let keys: Vec<_> = history.iter().map(|entry| entry.key()).collect();
+        assert_eq!(
+            vec![
+                EntryKey::Transaction(1, 1),
+                EntryKey::Transaction(1, 2),
+                EntryKey::Fee(1, 1),
+            ],
+            keys
+        );
+        let snapshot = ledger.get_client_snapshot(1)[0];
+        assert_eq!((amount("4"), amount("1")), (snapshot.total, snapshot.fees));
+
+        // A fee which can't be charged leaves no withdrawal behind either
+        ledger.fee_txs.insert(1, Tx::MAX);
+        assert!(matches!(
+            ledger.process_transaction(Transaction::Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: amount("1"),
+                currency: Currency::NONE,
+            }),
+            Err(Error::FeeTxExhausted(1))
+        ));
+        assert!(ledger.get_entry(1, 3)?.is_none());
+        assert_eq!(amount("4"), ledger.get_client_snapshot(1)[0].total);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,247 @@
+//! Fixed point representation of money. Every amount is stored as a whole number
+//! of ten-thousandths so that repeated arithmetic never drifts the way floating
+//! point totals do.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize, de::Visitor};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ParseAmountError {
+    #[error("Empty amount")]
+    Empty,
+
+    #[error("Invalid amount: {0}")]
+    Invalid(String),
+
+    #[error("Amount has more than four decimal places: {0}")]
+    Precision(String),
+
+    #[error("Amount out of range: {0}")]
+    OutOfRange(String),
+}
+
+/// A signed amount of money with four decimal places of precision
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    /// Number of digits kept after the decimal point
+    pub const DECIMALS: usize = 4;
+
+    /// Number of raw units in a single whole unit of currency
+    pub const SCALE: i64 = 10_000;
+
+    pub const ZERO: Amount = Amount(0);
+
+    pub fn checked_add(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_add(rhs.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, rhs: Amount) -> Option<Amount> {
+        self.0.checked_sub(rhs.0).map(Amount)
+    }
+
+    pub fn checked_neg(self) -> Option<Amount> {
+        self.0.checked_neg().map(Amount)
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+
+    /// The number of ten-thousandths, for fixed width encodings
+    pub fn to_raw(self) -> i64 {
+        self.0
+    }
+
+    /// An amount of `raw` ten-thousandths, as returned by [`Amount::to_raw`]
+    pub fn from_raw(raw: i64) -> Amount {
+        Amount(raw)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            Err(ParseAmountError::Empty)?
+        }
+
+        let (negative, unsigned) = match s.as_bytes()[0] {
+            b'-' => (true, &s[1..]),
+            b'+' => (false, &s[1..]),
+            _ => (false, s),
+        };
+
+        let (whole, fraction) = match unsigned.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (unsigned, ""),
+        };
+
+        let all_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (whole.is_empty() && fraction.is_empty()) || !all_digits(whole) || !all_digits(fraction)
+        {
+            Err(ParseAmountError::Invalid(s.to_string()))?
+        }
+
+        // Extra digits are only acceptable when they don't carry any value
+        if fraction.len() > Self::DECIMALS
+            && fraction.bytes().skip(Self::DECIMALS).any(|b| b != b'0')
+        {
+            Err(ParseAmountError::Precision(s.to_string()))?
+        }
+
+        let out_of_range = || ParseAmountError::OutOfRange(s.to_string());
+
+        let mut raw: i64 = 0;
+        for b in whole.bytes() {
+            raw = raw
+                .checked_mul(10)
+                .and_then(|r| r.checked_add(i64::from(b - b'0')))
+                .ok_or_else(out_of_range)?;
+        }
+
+        // Pad the fraction out to exactly four digits
+        let mut digits = fraction.bytes().chain(std::iter::repeat(b'0'));
+        for _ in 0..Self::DECIMALS {
+            let b = digits.next().expect("Padded with zeros");
+            raw = raw
+                .checked_mul(10)
+                .and_then(|r| r.checked_add(i64::from(b - b'0')))
+                .ok_or_else(out_of_range)?;
+        }
+
+        if negative {
+            raw = -raw;
+        }
+
+        Ok(Amount(raw))
+    }
+}
+
+impl Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let magnitude = self.0.unsigned_abs();
+        let scale = Self::SCALE as u64;
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            magnitude / scale,
+            magnitude % scale,
+            width = Self::DECIMALS
+        )
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a decimal amount with at most four decimal places")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.checked_mul(Amount::SCALE)
+            .map(Amount)
+            .ok_or_else(|| E::custom(ParseAmountError::OutOfRange(v.to_string())))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        i64::try_from(v)
+            .ok()
+            .and_then(|v| v.checked_mul(Amount::SCALE))
+            .map(Amount)
+            .ok_or_else(|| E::custom(ParseAmountError::OutOfRange(v.to_string())))
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::amount::{Amount, ParseAmountError};
+
+    #[test]
+    fn parse_and_format() -> Result<()> {
+        assert_eq!("1.0000", "1".parse::<Amount>()?.to_string());
+        assert_eq!("1.5000", "1.5".parse::<Amount>()?.to_string());
+        assert_eq!("0.0001", "0.0001".parse::<Amount>()?.to_string());
+        assert_eq!("0.5000", ".5".parse::<Amount>()?.to_string());
+        assert_eq!("-2.2500", "-2.25".parse::<Amount>()?.to_string());
+        assert_eq!("3.1000", "3.10000".parse::<Amount>()?.to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_bad_input() {
+        assert_eq!(Err(ParseAmountError::Empty), "".parse::<Amount>());
+        assert!(matches!(
+            "1.00001".parse::<Amount>(),
+            Err(ParseAmountError::Precision(_))
+        ));
+        assert!(matches!(
+            "1e5".parse::<Amount>(),
+            Err(ParseAmountError::Invalid(_))
+        ));
+        assert!(matches!(
+            ".".parse::<Amount>(),
+            Err(ParseAmountError::Invalid(_))
+        ));
+        assert!(matches!(
+            "99999999999999999999".parse::<Amount>(),
+            Err(ParseAmountError::OutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn many_small_deposits_do_not_drift() -> Result<()> {
+        let cent: Amount = "0.01".parse()?;
+        let mut total = Amount::ZERO;
+        for _ in 0..10_000 {
+            total = total.checked_add(cent).unwrap();
+        }
+
+        assert_eq!("100.0000", total.to_string());
+
+        Ok(())
+    }
+}
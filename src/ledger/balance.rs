@@ -2,15 +2,23 @@
 
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::ledger::{Client, Tx};
+use crate::ledger::{Client, Tx, amount::Amount, currency::Currency};
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
+    #[error("Amount must be positive")]
+    NonPositiveAmount,
+
     #[error("Insufficient funds")]
     InsufficientFunds,
 
+    #[error("Credit limit exceeded")]
+    CreditLimitExceeded,
+
     #[error("Account locked")]
     AccountLocked,
 
@@ -19,45 +27,75 @@ pub enum Error {
 
     #[error("No hold on Tx: {0}")]
     NoHoldError(Tx),
+
+    #[error("Balance overflow")]
+    Overflow,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct BalanceSnapshot {
     pub client: Client,
-    pub available: f64,
-    pub held: f64,
-    pub total: f64,
+    pub currency: Currency,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
     pub locked: bool,
+    pub credit_used: Amount,
+    pub fees: Amount,
 }
 
 /// Struct for tracking the underlying balance of a client
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Balance {
     /// Client which owns this balance entry
     client: Client,
 
+    /// Currency every amount within this balance is held in
+    currency: Currency,
+
     /// Total balance within this account
-    total: f64,
+    total: Amount,
 
     /// Track individual holds on transactions
-    holds: HashMap<Tx, f64>,
+    holds: HashMap<Tx, Amount>,
 
     /// Is this account locked
     locked: bool,
+
+    /// Every fee charged against this balance
+    #[serde(default)]
+    fees: Amount,
+
+    /// How far below zero withdrawals may take the total. Limits are configuration
+    /// rather than state, so they're set again on every run
+    #[serde(skip)]
+    credit_limit: Amount,
 }
 
 impl Balance {
-    pub fn new(client: Client) -> Self {
+    pub fn new(client: Client, currency: Currency) -> Self {
         Balance {
             client,
-            total: 0f64,
+            currency,
+            total: Amount::ZERO,
             holds: HashMap::new(),
             locked: false,
+            fees: Amount::ZERO,
+            credit_limit: Amount::ZERO,
         }
     }
 
-    pub fn available(&self) -> f64 {
-        self.total - self.held()
+    pub fn client(&self) -> Client {
+        self.client
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn available(&self) -> Amount {
+        self.checked_available(self.total, &self.holds)
+            .expect("Validated on every change to the balance")
     }
 
     /// The total amount of money being held in place
@@ -65,120 +103,353 @@ impl Balance {
     /// is not considered held. It's considered withdrawn. To count it as held
     /// would improperly increase the amount of money available for subsequent
     /// withdrawals putting the account servicer at risk.
-    pub fn held(&self) -> f64 {
-        let mut total_held = 0f64;
-        for v in self.holds.values() {
-            if *v > 0f64 {
-                total_held += *v
-            }
-        }
-        total_held
+    pub fn held(&self) -> Amount {
+        Self::checked_held(&self.holds).expect("Validated on every change to the balance")
     }
 
     pub fn locked(&self) -> bool {
         self.locked
     }
 
+    pub fn credit_limit(&self) -> Amount {
+        self.credit_limit
+    }
+
+    /// Allow withdrawals to take the total as far as `limit` below zero
+    pub fn set_credit_limit(&mut self, limit: Amount) {
+        self.credit_limit = limit;
+    }
+
+    /// How much of the credit limit the client is drawing on
+    pub fn credit_used(&self) -> Amount {
+        match self.total.checked_neg() {
+            Some(owed) if owed.is_positive() => owed,
+            _ => Amount::ZERO,
+        }
+    }
+
+    /// The sum of every fee charged against this balance
+    pub fn fees(&self) -> Amount {
+        self.fees
+    }
+
     pub fn lock_balance(&mut self) {
         self.locked = true;
     }
 
+    pub fn unlock_balance(&mut self) {
+        self.locked = false;
+    }
+
     /// Add funds to this balance
-    pub fn deposit(&mut self, amount: f64) -> Result<(), Error> {
+    pub fn deposit(&mut self, amount: Amount) -> Result<(), Error> {
+        positive(amount)?;
         if self.locked {
             Err(Error::AccountLocked)?
         }
 
-        self.total += amount;
+        let total = self.total.checked_add(amount).ok_or(Error::Overflow)?;
+        self.checked_available(total, &self.holds)?;
+        self.total = total;
 
         Ok(())
     }
 
-    pub fn withdraw(&mut self, amount: f64) -> Result<(), Error> {
-        if self.total < amount {
-            Err(Error::InsufficientFunds)?
-        }
+    /// Remove funds from this balance. Clients with a credit limit may withdraw past zero
+    /// down to the negated limit
+    pub fn withdraw(&mut self, amount: Amount) -> Result<(), Error> {
+        positive(amount)?;
+
+        // A total too low to represent is well past any limit
+        let floor = self.credit_limit.checked_neg().ok_or(Error::Overflow)?;
+        let total = match self.total.checked_sub(amount) {
+            Some(total) if total >= floor => total,
+            _ if self.credit_limit.is_positive() => Err(Error::CreditLimitExceeded)?,
+            _ => Err(Error::InsufficientFunds)?,
+        };
 
         if self.locked {
             Err(Error::AccountLocked)?
         }
 
-        self.total -= amount;
+        self.checked_available(total, &self.holds)?;
+        self.total = total;
+
+        Ok(())
+    }
+
+    /// Manual correction by an operator. Unlike a deposit this applies to a locked balance
+    pub fn credit(&mut self, amount: Amount) -> Result<(), Error> {
+        positive(amount)?;
+        let total = self.total.checked_add(amount).ok_or(Error::Overflow)?;
+        self.checked_available(total, &self.holds)?;
+        self.total = total;
+
+        Ok(())
+    }
+
+    /// Manual correction by an operator. Unlike a withdrawal this applies to a locked
+    /// balance and may take the balance negative
+    pub fn debit(&mut self, amount: Amount) -> Result<(), Error> {
+        positive(amount)?;
+        let total = self.total.checked_sub(amount).ok_or(Error::Overflow)?;
+        self.checked_available(total, &self.holds)?;
+        self.total = total;
 
         Ok(())
     }
 
-    pub fn hold(&mut self, tx: Tx, amount: f64) -> Result<(), Error> {
+    /// Remove a fee from this balance. Like a debit this applies to a locked balance and
+    /// may take the balance negative
+    pub fn charge_fee(&mut self, amount: Amount) -> Result<(), Error> {
+        let fees = self.fees.checked_add(amount).ok_or(Error::Overflow)?;
+        self.debit(amount)?;
+        self.fees = fees;
+
+        Ok(())
+    }
+
+    pub fn hold(&mut self, tx: Tx, amount: Amount) -> Result<(), Error> {
         if self.holds.contains_key(&tx) {
             Err(Error::MultiHoldError(tx))?;
         }
 
         self.holds.insert(tx, amount);
 
+        // Back the hold out again if it can't be represented
+        if let Err(e) = self.checked_available(self.total, &self.holds) {
+            self.holds.remove(&tx);
+            Err(e)?
+        }
+
+        Ok(())
+    }
+
+    /// Return `amount` which an earlier [`Balance::apply_hold`] removed from the total.
+    /// Like a credit this applies to a locked balance
+    pub fn reverse_charge_back(&mut self, amount: Amount) -> Result<(), Error> {
+        let total = self.total.checked_add(amount).ok_or(Error::Overflow)?;
+        self.checked_available(total, &self.holds)?;
+        self.total = total;
+
         Ok(())
     }
 
-    pub fn remove_hold(&mut self, tx: Tx) -> Result<(), Error> {
-        if !self.holds.contains_key(&tx) {
-            Err(Error::NoHoldError(tx))?;
+    /// Add to the amount already held on a transaction
+    pub fn extend_hold(&mut self, tx: Tx, amount: Amount) -> Result<(), Error> {
+        let held = *self.holds.get(&tx).ok_or(Error::NoHoldError(tx))?;
+        let extended = held.checked_add(amount).ok_or(Error::Overflow)?;
+
+        self.holds.insert(tx, extended);
+        if let Err(e) = self.checked_available(self.total, &self.holds) {
+            self.holds.insert(tx, held);
+            Err(e)?
         }
 
-        self.holds.remove(&tx);
+        Ok(())
+    }
+
+    /// Release `amount` of the hold on a transaction, leaving the rest held
+    pub fn release_part_of_hold(&mut self, tx: Tx, amount: Amount) -> Result<(), Error> {
+        self.reduce_hold(tx, amount, false)
+    }
+
+    /// Remove `amount` of the hold on a transaction from the total, leaving the rest held
+    pub fn apply_part_of_hold(&mut self, tx: Tx, amount: Amount) -> Result<(), Error> {
+        self.reduce_hold(tx, amount, true)
+    }
+
+    fn reduce_hold(&mut self, tx: Tx, amount: Amount, apply: bool) -> Result<(), Error> {
+        let held = *self.holds.get(&tx).ok_or(Error::NoHoldError(tx))?;
+        let reduced = held.checked_sub(amount).ok_or(Error::Overflow)?;
+        let total = match apply {
+            true => self.total.checked_sub(amount).ok_or(Error::Overflow)?,
+            false => self.total,
+        };
+
+        self.holds.insert(tx, reduced);
+        match self.checked_available(total, &self.holds) {
+            Ok(_) => self.total = total,
+            Err(e) => {
+                self.holds.insert(tx, held);
+                Err(e)?
+            }
+        }
 
         Ok(())
     }
 
-    pub fn apply_hold(&mut self, tx: Tx) -> Result<(), Error> {
-        if !self.holds.contains_key(&tx) {
-            Err(Error::NoHoldError(tx))?;
+    /// Release the hold on a transaction, returning the amount which was held
+    pub fn remove_hold(&mut self, tx: Tx) -> Result<Amount, Error> {
+        let amount = match self.holds.remove(&tx) {
+            Some(amount) => amount,
+            None => Err(Error::NoHoldError(tx))?,
+        };
+
+        if let Err(e) = self.checked_available(self.total, &self.holds) {
+            self.holds.insert(tx, amount);
+            Err(e)?
         }
 
-        self.total -= self.holds.get(&tx).expect("Checked in if clause");
-        self.holds.remove(&tx);
+        Ok(amount)
+    }
 
-        Ok(())
+    /// Remove the amount held on a transaction from the total, returning the amount
+    pub fn apply_hold(&mut self, tx: Tx) -> Result<Amount, Error> {
+        let amount = match self.holds.remove(&tx) {
+            Some(amount) => amount,
+            None => Err(Error::NoHoldError(tx))?,
+        };
+
+        let total = self.total.checked_sub(amount).ok_or(Error::Overflow);
+        match total.and_then(|total| self.checked_available(total, &self.holds).map(|_| total)) {
+            Ok(total) => self.total = total,
+            Err(e) => {
+                self.holds.insert(tx, amount);
+                Err(e)?
+            }
+        }
+
+        Ok(amount)
     }
 
     pub fn snapshot(&self) -> BalanceSnapshot {
         BalanceSnapshot {
             client: self.client,
+            currency: self.currency,
             available: self.available(),
             held: self.held(),
             total: self.total,
             locked: self.locked,
+            credit_used: self.credit_used(),
+            fees: self.fees,
         }
     }
+
+    /// Sum of the positive holds, or an overflow error if it can't be represented
+    fn checked_held(holds: &HashMap<Tx, Amount>) -> Result<Amount, Error> {
+        holds
+            .values()
+            .filter(|v| v.is_positive())
+            .try_fold(Amount::ZERO, |acc, v| acc.checked_add(*v))
+            .ok_or(Error::Overflow)
+    }
+
+    /// Every change to the balance is checked through here before it's committed so that
+    /// [`Balance::available`] and [`Balance::held`] can never overflow when read
+    fn checked_available(
+        &self,
+        total: Amount,
+        holds: &HashMap<Tx, Amount>,
+    ) -> Result<Amount, Error> {
+        let held = Self::checked_held(holds)?;
+        total.checked_sub(held).ok_or(Error::Overflow)
+    }
+}
+
+/// Deposits, withdrawals and manual corrections only move money one way. A negative
+/// amount would move it the other way, so they're refused rather than applied
+fn positive(amount: Amount) -> Result<(), Error> {
+    match amount.is_positive() {
+        true => Ok(()),
+        false => Err(Error::NonPositiveAmount),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use anyhow::Result;
 
-    use crate::ledger::balance::Balance;
+    use crate::ledger::{
+        amount::Amount,
+        balance::{Balance, Error},
+        currency::Currency,
+    };
 
     #[test]
     fn deposit_and_withdraw() -> Result<()> {
-        let mut b = Balance::new(0);
+        let mut b = Balance::new(0, Currency::NONE);
 
-        b.deposit(100f64)?;
-        b.withdraw(10f64)?;
+        b.deposit("100".parse()?)?;
+        b.withdraw("10".parse()?)?;
 
-        assert_eq!(90f64, b.available());
+        assert_eq!("90".parse::<Amount>()?, b.available());
 
         Ok(())
     }
 
     #[test]
     fn deposit_withdraw_hold() -> Result<()> {
-        let mut b = Balance::new(0);
+        let mut b = Balance::new(0, Currency::NONE);
 
-        b.deposit(100f64)?;
-        b.withdraw(10f64)?;
+        b.deposit("100".parse()?)?;
+        b.withdraw("10".parse()?)?;
 
         // Place a hold on the withdrawal
-        b.hold(2, -10f64)?;
+        b.hold(2, "-10".parse()?)?;
+
+        assert_eq!("90".parse::<Amount>()?, b.available());
+
+        Ok(())
+    }
+
+    #[test]
+    fn withdraw_into_credit() -> Result<()> {
+        let mut b = Balance::new(0, Currency::NONE);
+        b.set_credit_limit("50".parse()?);
+
+        b.deposit("10".parse()?)?;
+        b.withdraw("40".parse()?)?;
+        assert_eq!("-30".parse::<Amount>()?, b.available());
+        assert_eq!("30".parse::<Amount>()?, b.credit_used());
+
+        assert!(matches!(
+            b.withdraw("20.0001".parse()?),
+            Err(Error::CreditLimitExceeded)
+        ));
+        b.withdraw("20".parse()?)?;
+        assert_eq!("50".parse::<Amount>()?, b.credit_used());
+
+        // Without a limit the balance can't go below zero at all
+        let mut b = Balance::new(0, Currency::NONE);
+        b.deposit("10".parse()?)?;
+        assert!(matches!(
+            b.withdraw("11".parse()?),
+            Err(Error::InsufficientFunds)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn overflow_is_an_error() -> Result<()> {
+        let mut b = Balance::new(0, Currency::NONE);
+
+        let max: Amount = "922337203685477.5807".parse()?;
+        b.deposit(max)?;
+        assert!(matches!(b.deposit("0.0001".parse()?), Err(Error::Overflow)));
+
+        // The failed deposit leaves the balance untouched
+        assert_eq!(max, b.available());
+
+        Ok(())
+    }
+
+    #[test]
+    fn amounts_must_be_positive() -> Result<()> {
+        let mut b = Balance::new(0, Currency::NONE);
+        b.deposit("10".parse()?)?;
+
+        for amount in ["-1000", "0"] {
+            let amount: Amount = amount.parse()?;
+            assert!(matches!(b.deposit(amount), Err(Error::NonPositiveAmount)));
+            assert!(matches!(b.withdraw(amount), Err(Error::NonPositiveAmount)));
+            assert!(matches!(b.credit(amount), Err(Error::NonPositiveAmount)));
+            assert!(matches!(b.debit(amount), Err(Error::NonPositiveAmount)));
+        }
 
-        assert_eq!(90f64, b.available());
+        // Nothing was created or destroyed along the way
+        assert_eq!("10".parse::<Amount>()?, b.available());
 
         Ok(())
     }
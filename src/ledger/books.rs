@@ -0,0 +1,550 @@
+//! Sub module for an optional double-entry view of the ledger. A [`Balance`] only adds to
+//! and subtracts from its total, which says nothing of where the money came from or went.
+//! With books kept, every change to a balance is also recorded as a [`Posting`] which
+//! debits one account and credits another by the same amount, so the trial balance of
+//! every account must always net to zero. The books are checked against the balances by
+//! [`Ledger::unbalanced_accounts`].
+//!
+//! Each client has an account holding what's available to them. Held funds, the business'
+//! cash, and the other side of charge backs, fees and manual corrections are system
+//! accounts shared by every client. Every account is kept separately in each currency.
+//!
+//! [`Balance`]: crate::ledger::balance::Balance
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ledger::{Client, Error, Ledger, Tx, amount::Amount, balance, currency::Currency};
+
+/// An account which postings move money between
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Account {
+    /// Money owed to the client which they're free to withdraw
+    Client(Client),
+    /// Money the business holds. Deposits arrive here and withdrawals leave from here
+    Cash,
+    /// Client money set aside while a dispute on it is open
+    HeldFunds,
+    /// Money returned to clients by charged back withdrawals
+    ChargeBackLosses,
+    /// Fees charged to clients
+    FeeIncome,
+    /// The other side of manual corrections made by operators
+    Adjustments,
+    /// The other side of the balances a ledger already had when books were started on it
+    OpeningBalances,
+}
+
+impl Display for Account {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Account::Client(client) => write!(f, "client:{}", client),
+            Account::Cash => f.write_str("cash"),
+            Account::HeldFunds => f.write_str("held_funds"),
+            Account::ChargeBackLosses => f.write_str("chargeback_losses"),
+            Account::FeeIncome => f.write_str("fee_income"),
+            Account::Adjustments => f.write_str("adjustments"),
+            Account::OpeningBalances => f.write_str("opening_balances"),
+        }
+    }
+}
+
+/// A movement of money from one account to another
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Posting {
+    /// The transaction which moved the money. Postings for a dispute, resolve or charge
+    /// back carry the id of the entry in dispute, fees carry the fee's own id and opening
+    /// balances carry 0
+    pub tx: Tx,
+
+    /// The client the transaction belongs to
+    pub client: Client,
+
+    pub currency: Currency,
+
+    /// The account the money moves into
+    pub debit: Account,
+
+    /// The account the money moves out of
+    pub credit: Account,
+
+    /// Always positive
+    pub amount: Amount,
+}
+
+/// Everything debited from and credited to one account in one currency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrialBalance {
+    pub currency: Currency,
+    pub account: Account,
+    pub debit: Amount,
+    pub credit: Amount,
+}
+
+impl TrialBalance {
+    /// Debits less credits, if it can be represented
+    pub fn net(&self) -> Option<Amount> {
+        self.debit.checked_sub(self.credit)
+    }
+}
+
+/// Every posting made, along with running totals for each account
+#[derive(Debug, Clone, Default)]
+pub struct Books {
+    postings: Vec<Posting>,
+
+    /// Total debits and credits of each account
+    totals: BTreeMap<(Currency, Account), (Amount, Amount)>,
+}
+
+impl Books {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuild books from postings which were made earlier
+    pub fn from_postings(postings: Vec<Posting>) -> Result<Self, balance::Error> {
+        let mut books = Books::new();
+        for posting in postings {
+            books.post(posting)?;
+        }
+        Ok(books)
+    }
+
+    /// Record a posting. Fails without recording anything if a total can't be represented
+    pub fn post(&mut self, posting: Posting) -> Result<(), balance::Error> {
+        let debit_key = (posting.currency, posting.debit);
+        let credit_key = (posting.currency, posting.credit);
+
+        let (debits, _) = self.totals.get(&debit_key).copied().unwrap_or_default();
+        let (_, credits) = self.totals.get(&credit_key).copied().unwrap_or_default();
+        let debits = debits
+            .checked_add(posting.amount)
+            .ok_or(balance::Error::Overflow)?;
+        let credits = credits
+            .checked_add(posting.amount)
+            .ok_or(balance::Error::Overflow)?;
+
+        self.totals.entry(debit_key).or_default().0 = debits;
+        self.totals.entry(credit_key).or_default().1 = credits;
+        self.postings.push(posting);
+
+        Ok(())
+    }
+
+    /// Record every posting from other books after the postings already made
+    pub fn extend(&mut self, other: Books) -> Result<(), balance::Error> {
+        for posting in other.postings {
+            self.post(posting)?;
+        }
+        Ok(())
+    }
+
+    /// Take back every posting after the first `len`, as though they were never made
+    pub(crate) fn truncate(&mut self, len: usize) {
+        while self.postings.len() > len {
+            let posting = self.postings.pop().expect("Longer than len");
+            for (key, debit) in [
+                ((posting.currency, posting.debit), true),
+                ((posting.currency, posting.credit), false),
+            ] {
+                let totals = self.totals.get_mut(&key).expect("Posted to");
+                let total = match debit {
+                    true => &mut totals.0,
+                    false => &mut totals.1,
+                };
+                *total = total
+                    .checked_sub(posting.amount)
+                    .expect("Totals include the posting");
+                if *totals == (Amount::ZERO, Amount::ZERO) {
+                    self.totals.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Every posting in the order it was made
+    pub fn postings(&self) -> &[Posting] {
+        &self.postings
+    }
+
+    /// The totals of every account, ordered by currency and then account
+    pub fn trial_balance(&self) -> Vec<TrialBalance> {
+        self.totals
+            .iter()
+            .map(|(&(currency, account), &(debit, credit))| TrialBalance {
+                currency,
+                account,
+                debit,
+                credit,
+            })
+            .collect()
+    }
+
+    /// Debits less credits of one account, if it can be represented
+    fn net(&self, currency: Currency, account: Account) -> Option<Amount> {
+        let (debit, credit) = self
+            .totals
+            .get(&(currency, account))
+            .copied()
+            .unwrap_or_default();
+        debit.checked_sub(credit)
+    }
+
+    /// Move the postings of clients for which `keep` holds into new books
+    pub(crate) fn split_off(&mut self, keep: impl Fn(Client) -> bool) -> Books {
+        let (kept, rest) = std::mem::take(&mut self.postings)
+            .into_iter()
+            .partition(|p| keep(p.client));
+        *self = Books::from_postings(rest).expect("Totals of a subset are representable");
+        Books::from_postings(kept).expect("Totals of a subset are representable")
+    }
+}
+
+impl Ledger {
+    /// Keep double-entry books alongside the balances from here on. Books which were
+    /// restored with the ledger's state are kept. Otherwise the balances the ledger already
+    /// has are posted as opening balances, so the books agree with them from the start
+    pub fn with_double_entry(mut self) -> Result<Self, Error> {
+        if self.books.is_some() {
+            return Ok(self);
+        }
+        self.books = Some(Books::new());
+
+        let mut opening: Vec<_> = self
+            .balance
+            .values()
+            .map(|b| (b.client(), b.currency(), b.available(), b.held()))
+            .collect();
+        opening.sort_by_key(|&(client, currency, ..)| (client, currency));
+
+        for (client, currency, available, held) in opening {
+            let key = (client, 0);
+            let debit = Account::OpeningBalances;
+            self.post(key, currency, debit, Account::Client(client), available)?;
+            self.post(key, currency, debit, Account::HeldFunds, held)?;
+        }
+
+        Ok(self)
+    }
+
+    /// Accounts on which the books disagree with the balances. Each client's account must
+    /// be credited with what's available to them, and held funds with everything held in
+    /// that currency. Empty when books aren't kept
+    pub fn unbalanced_accounts(&self) -> Vec<(Currency, Account)> {
+        let Some(books) = self.books.as_ref() else {
+            return Vec::new();
+        };
+
+        // What each account should net to, starting from every account posted to
+        let mut expected: BTreeMap<(Currency, Account), Option<Amount>> = books
+            .totals
+            .keys()
+            .filter(|(_, account)| matches!(account, Account::Client(_) | Account::HeldFunds))
+            .map(|key| (*key, Some(Amount::ZERO)))
+            .collect();
+        for balance in self.balance.values() {
+            let currency = balance.currency();
+            expected.insert(
+                (currency, Account::Client(balance.client())),
+                balance.available().checked_neg(),
+            );
+            let held = expected
+                .entry((currency, Account::HeldFunds))
+                .or_insert(Some(Amount::ZERO));
+            *held = held.and_then(|h| h.checked_sub(balance.held()));
+        }
+
+        expected
+            .into_iter()
+            .filter(|&((currency, account), net)| {
+                net.is_none() || books.net(currency, account) != net
+            })
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    /// The ledger's books, if they're being kept
+    pub fn books(&self) -> Option<&Books> {
+        self.books.as_ref()
+    }
+
+    /// Record a movement of money for the client's transaction, if books are being kept.
+    /// A negative amount moves the other way
+    pub(crate) fn post(
+        &mut self,
+        (client, tx): (Client, Tx),
+        currency: Currency,
+        debit: Account,
+        credit: Account,
+        amount: Amount,
+    ) -> Result<(), Error> {
+        let Some(books) = self.books.as_mut() else {
+            return Ok(());
+        };
+
+        let (debit, credit, amount) = match amount.checked_neg() {
+            _ if amount.is_positive() => (debit, credit, amount),
+            Some(negated) if negated.is_positive() => (credit, debit, negated),
+            Some(_) => return Ok(()),
+            None => Err(balance::Error::Overflow)?,
+        };
+
+        books.post(Posting {
+            tx,
+            client,
+            currency,
+            debit,
+            credit,
+            amount,
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{
+        AdminAction, Ledger, Transaction,
+        amount::Amount,
+        books::{Account, Posting},
+        currency::Currency,
+        fees::{FeeRule, FeeSchedule, FeeTrigger},
+        store::MemoryStore,
+    };
+
+    fn amount(s: &str) -> Amount {
+        s.parse().expect("valid amount literal")
+    }
+
+    fn deposit(client: u16, tx: u32, value: &str) -> Transaction {
+        Transaction::Deposit {
+            client,
+            tx,
+            amount: amount(value),
+            currency: Currency::NONE,
+        }
+    }
+
+    #[test]
+    fn client_accounts_match_balances() -> Result<()> {
+        let mut ledger = Ledger::new().with_double_entry()?;
+        ledger.process_transaction(deposit(1, 1, "100"))?;
+        ledger.process_transaction(deposit(1, 2, "50"))?;
+        ledger.process_transaction(Transaction::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: amount("30"),
+            currency: Currency::NONE,
+        })?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 2,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::Resolve {
+            client: 1,
+            tx: 2,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 3,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 1,
+            tx: 3,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::Admin {
+            client: 1,
+            tx: 4,
+            action: AdminAction::Credit {
+                amount: amount("5"),
+                currency: Currency::NONE,
+            },
+            operator: 1,
+            reason: "goodwill".to_string(),
+        })?;
+
+        let books = ledger.books().expect("books are kept");
+        assert!(ledger.unbalanced_accounts().is_empty());
+
+        let snapshot = ledger.get_client_snapshot(1)[0];
+        let line = |account| {
+            books
+                .trial_balance()
+                .into_iter()
+                .find(|l| l.account == account)
+                .expect("account was posted to")
+        };
+
+        // Client accounts are credited with what's owed to the client
+        assert_eq!(
+            snapshot.available,
+            line(Account::Client(1))
+                .net()
+                .unwrap()
+                .checked_neg()
+                .unwrap()
+        );
+        assert_eq!(
+            snapshot.held,
+            line(Account::HeldFunds)
+                .net()
+                .unwrap()
+                .checked_neg()
+                .unwrap()
+        );
+        assert_eq!(amount("30"), line(Account::ChargeBackLosses).debit);
+        assert_eq!(amount("120"), line(Account::Cash).net().unwrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn books_open_on_existing_balances() -> Result<()> {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(deposit(1, 1, "100"))?;
+        ledger.process_transaction(Transaction::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: amount("10"),
+            currency: Currency::NONE,
+        })?;
+        ledger.process_transaction(deposit(2, 3, "20"))?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 2,
+            tx: 3,
+            amount: Some(amount("5")),
+        })?;
+
+        let mut ledger = ledger.with_double_entry()?;
+        assert!(ledger.unbalanced_accounts().is_empty());
+        ledger.process_transaction(deposit(1, 4, "10"))?;
+        assert!(ledger.unbalanced_accounts().is_empty());
+
+        let books = ledger.books().expect("books are kept");
+        let opening = books
+            .trial_balance()
+            .into_iter()
+            .find(|l| l.account == Account::OpeningBalances)
+            .expect("opening balances were posted");
+        assert_eq!(amount("110"), opening.net().unwrap());
+
+        Ok(())
+    }
+
+    #[test]
+    fn books_which_disagree_are_reported() -> Result<()> {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(deposit(1, 1, "100"))?;
+        let mut saved = Vec::new();
+        ledger.save(&mut saved)?;
+
+        // Postings saved without the deposit which made the balance
+        let state = String::from_utf8(saved)?.replace(r#""clock""#, r#""postings":[],"clock""#);
+        let ledger = Ledger::load(state.as_bytes(), MemoryStore::default())?;
+        assert_eq!(
+            vec![(Currency::NONE, Account::Client(1))],
+            ledger.unbalanced_accounts()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn charge_back_returns_held_funds() -> Result<()> {
+        let mut ledger = Ledger::new().with_double_entry()?;
+        ledger.process_transaction(deposit(1, 1, "10"))?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 1,
+            tx: 1,
+            amount: None,
+        })?;
+
+        let books = ledger.books().expect("books are kept");
+        assert_eq!(
+            &Posting {
+                tx: 1,
+                client: 1,
+                currency: Currency::NONE,
+                debit: Account::HeldFunds,
+                credit: Account::Cash,
+                amount: amount("10"),
+            },
+            books.postings().last().expect("charge back was posted")
+        );
+        assert!(ledger.unbalanced_accounts().is_empty());
+        assert!(
+            books
+                .trial_balance()
+                .iter()
+                .all(|l| l.net() == Some(Amount::ZERO))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_postings_are_taken_back() -> Result<()> {
+        let mut fees = FeeSchedule::new();
+        fees.push(FeeRule {
+            on: FeeTrigger::Withdrawal,
+            min: Amount::ZERO,
+            max: None,
+            currency: None,
+            fee: amount("461168601842738.7904"),
+        });
+        let mut ledger = Ledger::new().with_fee_schedule(fees).with_double_entry()?;
+        for client in [1, 2] {
+            ledger.process_transaction(deposit(client, u32::from(client), "10"))?;
+        }
+        ledger.process_transaction(Transaction::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: amount("1"),
+            currency: Currency::NONE,
+        })?;
+        let posted = ledger.books().expect("books are kept").postings().len();
+
+        // The withdrawal is posted before its fee overflows the fee income
+        assert!(
+            ledger
+                .process_transaction(Transaction::Withdrawal {
+                    client: 2,
+                    tx: 4,
+                    amount: amount("1"),
+                    currency: Currency::NONE,
+                })
+                .is_err()
+        );
+        assert_eq!(amount("10"), ledger.get_client_snapshot(2)[0].total);
+        let books = ledger.books().expect("books are kept");
+        assert_eq!(posted, books.postings().len());
+        assert!(ledger.unbalanced_accounts().is_empty());
+        assert!(
+            books
+                .trial_balance()
+                .iter()
+                .all(|l| l.account != Account::Client(2) || l.net() == Some(amount("-10")))
+        );
+
+        Ok(())
+    }
+}
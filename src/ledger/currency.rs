@@ -0,0 +1,106 @@
+//! Sub module for the currency a balance is held in
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize, de::Visitor};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid currency code: {0}")]
+pub struct ParseCurrencyError(String);
+
+/// A three letter ISO 4217 currency code. Transactions which don't name a currency
+/// are held in `XXX`, the code reserved for transactions without a currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const NONE: Currency = Currency(*b"XXX");
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.0).expect("Only ASCII letters are accepted")
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+impl FromStr for Currency {
+    type Err = ParseCurrencyError;
+
+    /// Codes are accepted in any case and normalized to upper case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; 3] = s
+            .as_bytes()
+            .try_into()
+            .map_err(|_| ParseCurrencyError(s.to_string()))?;
+
+        if !bytes.iter().all(u8::is_ascii_alphabetic) {
+            Err(ParseCurrencyError(s.to_string()))?
+        }
+
+        Ok(Currency(bytes.map(|b| b.to_ascii_uppercase())))
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+struct CurrencyVisitor;
+
+impl<'de> Visitor<'de> for CurrencyVisitor {
+    type Value = Currency;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a three letter currency code")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.parse().map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(CurrencyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::currency::Currency;
+
+    #[test]
+    fn parse_currency() -> Result<()> {
+        assert_eq!("EUR", "eur".parse::<Currency>()?.to_string());
+        assert_eq!("GBP", "GBP".parse::<Currency>()?.as_str());
+        assert!("EURO".parse::<Currency>().is_err());
+        assert!("U$D".parse::<Currency>().is_err());
+        assert_eq!(Currency::NONE, Currency::default());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+//! Sub module for the fees charged on withdrawals, disputes and charge backs. Fees are
+//! decided by the rules of a [`FeeSchedule`] and posted as their own [`Transaction::Fee`]
+//! entries, so they show up in a client's history alongside what they were charged for.
+//! The schedule is configuration rather than state, so it's given to the ledger again on
+//! every run.
+//!
+//! Fees are registered under [`EntryKey::Fee`], apart from the input's transactions, so
+//! their ids never collide with one an input may use. Each client's fees count up from 1
+//! on their own, so a fee gets the same id however the clients are split between workers.
+
+use serde::{Deserialize, Serialize};
+
+use crate::ledger::{
+    Client, Entry, EntryKey, Error, Ledger, Timestamp, Transaction, Tx, amount::Amount,
+    books::Account, currency::Currency,
+};
+
+/// The transactions a fee may be charged on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeTrigger {
+    Withdrawal,
+    Dispute,
+    ChargeBack,
+}
+
+/// A flat fee charged on one kind of transaction when its amount falls within a band
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FeeRule {
+    /// The kind of transaction the fee is charged on
+    pub on: FeeTrigger,
+
+    /// Smallest amount the rule applies to, inclusive
+    pub min: Amount,
+
+    /// Amount the rule stops applying at, exclusive. Unbounded when absent
+    pub max: Option<Amount>,
+
+    /// Currency the rule applies to. Applies to every currency when absent
+    pub currency: Option<Currency>,
+
+    /// The fee charged, in the currency of the transaction
+    pub fee: Amount,
+}
+
+impl FeeRule {
+    fn applies(&self, on: FeeTrigger, amount: Amount, currency: Currency) -> bool {
+        self.on == on
+            && amount >= self.min
+            && self.max.is_none_or(|max| amount < max)
+            && self.currency.is_none_or(|c| c == currency)
+    }
+}
+
+/// Rules for which transactions are charged a fee. The first rule which applies to a
+/// transaction decides its fee, and transactions no rule applies to are free
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeeSchedule(Vec<FeeRule>);
+
+impl FeeSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule, consulted after every rule already in the schedule
+    pub fn push(&mut self, rule: FeeRule) {
+        self.0.push(rule);
+    }
+
+    /// The fee charged on a transaction of `amount`, if any
+    pub fn fee_for(&self, on: FeeTrigger, amount: Amount, currency: Currency) -> Option<Amount> {
+        self.0
+            .iter()
+            .find(|rule| rule.applies(on, amount, currency))
+            .map(|rule| rule.fee)
+            .filter(|fee| fee.is_positive())
+    }
+}
+
+impl Ledger {
+    /// Replace the [`FeeSchedule`], under which nothing is charged a fee by default
+    pub fn with_fee_schedule(mut self, fees: FeeSchedule) -> Self {
+        self.fees = fees;
+        self
+    }
+
+    pub(crate) fn fee_schedule(&self) -> &FeeSchedule {
+        &self.fees
+    }
+
+    /// Charge fees under `fees` from now on, returning the schedule it replaces
+    pub(crate) fn replace_fee_schedule(&mut self, fees: FeeSchedule) -> FeeSchedule {
+        std::mem::replace(&mut self.fees, fees)
+    }
+
+    /// Charge the client the fee for a transaction of `amount`, if the schedule has one.
+    /// The fee gets a transaction id of its own, and its entry is returned to be written
+    /// to the store along with the transaction it was charged for
+    pub(crate) fn charge_fee(
+        &mut self,
+        on: FeeTrigger,
+        client: Client,
+        amount: Amount,
+        currency: Currency,
+        charged_for: Tx,
+        at: Option<Timestamp>,
+    ) -> Result<Option<Entry>, Error> {
+        let Some(fee) = self.fees.fee_for(on, amount, currency) else {
+            return Ok(None);
+        };
+
+        let tx = self.next_fee_tx(client)?;
+        self.balance_mut(client, currency).charge_fee(fee)?;
+        self.fee_txs.insert(client, tx);
+        let (debit, credit) = (Account::Client(client), Account::FeeIncome);
+        self.post((client, tx), currency, debit, credit, fee)?;
+
+        Ok(Some(Entry::new(
+            Transaction::Fee {
+                client,
+                tx,
+                amount: fee,
+                currency,
+                charged_for,
+            },
+            at,
+        )))
+    }
+
+    /// The client's fees take their ids counting up from 1, skipping any already used by
+    /// fees restored from an older state
+    fn next_fee_tx(&self, client: Client) -> Result<Tx, Error> {
+        let mut tx = match self.fee_txs.get(&client) {
+            Some(last) => last.checked_add(1),
+            None => Some(1),
+        };
+        while let Some(id) = tx
+            && self.transactions.contains(&EntryKey::Fee(client, id))
+        {
+            tx = id.checked_add(1);
+        }
+
+        tx.ok_or(Error::FeeTxExhausted(client))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{
+        EntryKey, Ledger, Transaction, TxStatus,
+        amount::Amount,
+        currency::Currency,
+        fees::{FeeRule, FeeSchedule, FeeTrigger},
+    };
+
+    fn amount(s: &str) -> Amount {
+        s.parse().expect("valid amount literal")
+    }
+
+    fn schedule() -> FeeSchedule {
+        let mut fees = FeeSchedule::new();
+        fees.push(FeeRule {
+            on: FeeTrigger::Withdrawal,
+            min: Amount::ZERO,
+            max: Some(amount("100")),
+            currency: None,
+            fee: amount("1"),
+        });
+        fees.push(FeeRule {
+            on: FeeTrigger::Withdrawal,
+            min: amount("100"),
+            max: None,
+            currency: None,
+            fee: amount("2.5"),
+        });
+        fees.push(FeeRule {
+            on: FeeTrigger::ChargeBack,
+            min: Amount::ZERO,
+            max: None,
+            currency: None,
+            fee: amount("15"),
+        });
+        fees
+    }
+
+    #[test]
+    fn fees_follow_amount_bands() -> Result<()> {
+        let mut ledger = Ledger::new().with_fee_schedule(schedule());
+        ledger.process_transaction(Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: amount("500"),
+            currency: Currency::NONE,
+        })?;
+        for (tx, withdrawn) in [(2, "99.9999"), (3, "100")] {
+            ledger.process_transaction(Transaction::Withdrawal {
+                client: 1,
+                tx,
+                amount: amount(withdrawn),
+                currency: Currency::NONE,
+            })?;
+        }
+
+        let snapshot = ledger.get_client_snapshot(1)[0];
+        assert_eq!(amount("3.5"), snapshot.fees);
+        assert_eq!(amount("296.5001"), snapshot.total);
+
+        // Each fee is an entry of its own, after the withdrawal it was charged for
+        let history = ledger.get_client_history(1, 0, usize::MAX)?;
+        assert_eq!(5, history.len());
+        assert!(matches!(
+            history[2].t,
+            Transaction::Fee {
+                tx: 1,
+                charged_for: 2,
+                ..
+            }
+        ));
+        assert!(matches!(
+            history[4].t,
+            Transaction::Fee {
+                tx: 2,
+                charged_for: 3,
+                ..
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn charge_back_fee_is_charged_on_a_frozen_account() -> Result<()> {
+        let mut ledger = Ledger::new().with_fee_schedule(schedule());
+        ledger.process_transaction(Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: amount("10"),
+            currency: Currency::NONE,
+        })?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 1,
+            tx: 1,
+            amount: None,
+        })?;
+
+        // Fees may take the balance negative, even once the account is frozen
+        let snapshot = ledger.get_client_snapshot(1)[0];
+        assert!(snapshot.locked);
+        assert_eq!(amount("-15"), snapshot.total);
+
+        let history = ledger.get_client_history(1, 0, usize::MAX)?;
+        let fee = history.last().expect("fee was posted");
+        assert_eq!(EntryKey::Fee(1, 1), fee.key());
+        assert_eq!(TxStatus::Active, fee.status());
+        assert_eq!(Some(amount("15")), fee.t.amount());
+
+        Ok(())
+    }
+
+    #[test]
+    fn fee_ids_are_kept_apart() -> Result<()> {
+        let mut ledger = Ledger::new().with_fee_schedule(schedule());
+        for client in [1, 2] {
+            ledger.process_transaction(Transaction::Deposit {
+                client,
+                tx: u32::MAX,
+                amount: amount("10"),
+                currency: Currency::NONE,
+            })?;
+            ledger.process_transaction(Transaction::Withdrawal {
+                client,
+                tx: 1,
+                amount: amount("1"),
+                currency: Currency::NONE,
+            })?;
+        }
+
+        // Each client's fees count up on their own, clear of the ids the input uses
+        for client in [1, 2] {
+            let history = ledger.get_client_history(client, 0, usize::MAX)?;
+            let keys: Vec<_> = history.iter().map(|entry| entry.key()).collect();
+            assert_eq!(
+                vec![
+                    EntryKey::Transaction(client, u32::MAX),
+                    EntryKey::Transaction(client, 1),
+                    EntryKey::Fee(client, 1),
+                ],
+                keys
+            );
+        }
+        assert_eq!(
+            Some(amount("10")),
+            ledger.get_entry(1, u32::MAX)?.and_then(|e| e.t.amount())
+        );
+
+        // An input may still take an id a fee already has
+        ledger.process_transaction(Transaction::Deposit {
+            client: 1,
+            tx: 2,
+            amount: amount("5"),
+            currency: Currency::NONE,
+        })?;
+        ledger.process_transaction(Transaction::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: amount("1"),
+            currency: Currency::NONE,
+        })?;
+        assert!(ledger.get_entry(1, 2)?.is_some());
+        let history = ledger.get_client_history(1, 0, usize::MAX)?;
+        assert_eq!(EntryKey::Fee(1, 2), history[history.len() - 1].key());
+
+        Ok(())
+    }
+}
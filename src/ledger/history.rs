@@ -0,0 +1,86 @@
+//! Sub module for looking back over what happened to a client's transactions. These are the
+//! queries support staff need to answer questions such as "what happened to tx 1234 for
+//! client 7" without reading through the whole input.
+
+use crate::ledger::{Client, Entry, EntryKey, Ledger, Tx, store};
+
+impl Ledger {
+    /// Find a single entry read from the input along with its status
+    pub fn get_entry(&self, client: Client, tx: Tx) -> Result<Option<Entry>, store::Error> {
+        self.transactions.get(&EntryKey::Transaction(client, tx))
+    }
+
+    /// A page of the client's entries in the order they were applied, along with the
+    /// transfers they received. `offset` entries are skipped and at most `limit` are returned
+    pub fn get_client_history(
+        &self,
+        client: Client,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Entry>, store::Error> {
+        self.transactions
+            .entries()
+            .filter(|e| {
+                e.as_ref().map_or(true, |e| {
+                    *e.t.client() == client || e.t.recipient() == Some(client)
+                })
+            })
+            .skip(offset)
+            .take(limit)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{Ledger, Transaction, TxStatus, currency::Currency};
+
+    fn deposit(client: u16, tx: u32) -> Transaction {
+        Transaction::Deposit {
+            client,
+            tx,
+            amount: "10".parse().unwrap(),
+            currency: Currency::NONE,
+        }
+    }
+
+    #[test]
+    fn entry_status() -> Result<()> {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(deposit(7, 1234))?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 7,
+            tx: 1234,
+            amount: None,
+        })?;
+
+        let entry = ledger.get_entry(7, 1234)?.expect("entry was registered");
+        assert_eq!(TxStatus::Disputed, entry.status());
+        assert_eq!(deposit(7, 1234), entry.t);
+        assert!(ledger.get_entry(8, 1234)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn history_is_paged_in_order() -> Result<()> {
+        let mut ledger = Ledger::new();
+        for tx in 1..=5 {
+            ledger.process_transaction(deposit(1, tx))?;
+            ledger.process_transaction(deposit(2, tx + 100))?;
+        }
+
+        let page: Vec<_> = ledger
+            .get_client_history(1, 1, 3)?
+            .iter()
+            .map(|e| *e.t.tx())
+            .collect();
+        assert_eq!(vec![2, 3, 4], page);
+        assert_eq!(1, ledger.get_client_history(1, 4, 3)?.len());
+        assert!(ledger.get_client_history(3, 0, 10)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+//! Sub module for the overdraft lines agreed with business clients. A client with a credit
+//! limit may withdraw past zero down to the negated limit. Limits are configuration rather
+//! than state, so they're given to the ledger again on every run.
+
+use std::collections::HashMap;
+
+use crate::ledger::{Client, Ledger, amount::Amount, currency::Currency};
+
+/// Credit limits for each client and currency. Anything without a limit can't go below zero
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CreditLimits(HashMap<(Client, Currency), Amount>);
+
+impl CreditLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow the client to withdraw as far as `limit` below zero in the currency
+    pub fn set(&mut self, client: Client, currency: Currency, limit: Amount) {
+        self.0.insert((client, currency), limit);
+    }
+
+    /// The client's limit in the currency, zero when none was agreed
+    pub fn get(&self, client: Client, currency: Currency) -> Amount {
+        self.0
+            .get(&(client, currency))
+            .copied()
+            .unwrap_or(Amount::ZERO)
+    }
+}
+
+impl Ledger {
+    /// Replace every client's credit limit. Balances which are already open take on their
+    /// new limit straight away
+    pub fn with_credit_limits(mut self, limits: CreditLimits) -> Self {
+        for ((client, currency), balance) in self.balance.iter_mut() {
+            balance.set_credit_limit(limits.get(*client, *currency));
+        }
+        self.limits = limits;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{
+        Error, Ledger, Transaction, amount::Amount, balance, currency::Currency,
+        limits::CreditLimits,
+    };
+
+    fn withdrawal(client: u16, tx: u32, amount: &str) -> Transaction {
+        Transaction::Withdrawal {
+            client,
+            tx,
+            amount: amount.parse().unwrap(),
+            currency: Currency::NONE,
+        }
+    }
+
+    #[test]
+    fn only_clients_with_a_limit_overdraw() -> Result<()> {
+        let mut limits = CreditLimits::new();
+        limits.set(1, Currency::NONE, "100".parse()?);
+
+        let mut ledger = Ledger::new().with_credit_limits(limits);
+        ledger.process_transaction(withdrawal(1, 1, "60"))?;
+        assert!(matches!(
+            ledger.process_transaction(withdrawal(1, 2, "60")),
+            Err(Error::BalanceError(balance::Error::CreditLimitExceeded))
+        ));
+        assert!(matches!(
+            ledger.process_transaction(withdrawal(2, 3, "1")),
+            Err(Error::BalanceError(balance::Error::InsufficientFunds))
+        ));
+
+        let snapshot = ledger.get_client_snapshot(1)[0];
+        assert_eq!("-60".parse::<Amount>()?, snapshot.total);
+        assert_eq!("60".parse::<Amount>()?, snapshot.credit_used);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+//! Sub module for the rules which decide what a dispute does. Different card programs
+//! treat disputes differently, so the ledger consults a [`DisputePolicy`] rather than
+//! hard coding them.
+
+use crate::ledger::{Error, Transaction, amount::Amount, balance};
+
+/// What happens to the client's account once a dispute is charged back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeBackEffect {
+    /// Freeze the account against all further client transactions
+    LockAccount,
+    /// Leave the account open
+    KeepOpen,
+}
+
+pub trait DisputePolicy: Send + Sync {
+    /// Whether the entry may be disputed at all
+    fn is_disputable(&self, entry: &Transaction) -> bool;
+
+    /// The amount to hold against the balance while `disputed` of the entry is in dispute.
+    /// Only positive holds reduce the available balance, see [`balance::Balance::held`].
+    /// Only called for disputable entries, and called again for each part of the dispute
+    /// which is settled on its own.
+    fn hold_amount(&self, entry: &Transaction, disputed: Amount) -> Result<Amount, Error>;
+
+    /// The effect on the account when the dispute on the entry is charged back
+    fn charge_back_effect(&self, entry: &Transaction) -> ChargeBackEffect;
+}
+
+/// The default rules. Deposits, withdrawals and transfers may all be disputed and a charge
+/// back freezes the account.
+///
+/// A disputed deposit holds the disputed amount as that money may be leaving the account. A
+/// disputed withdrawal holds the negated amount, as that money may be returning to the
+/// account, so nothing is held back from the client. A disputed transfer holds the disputed
+/// amount, which is held on the recipient.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StandardPolicy;
+
+impl DisputePolicy for StandardPolicy {
+    fn is_disputable(&self, entry: &Transaction) -> bool {
+        matches!(
+            entry,
+            Transaction::Deposit { .. }
+                | Transaction::Withdrawal { .. }
+                | Transaction::Transfer { .. }
+        )
+    }
+
+    fn hold_amount(&self, entry: &Transaction, disputed: Amount) -> Result<Amount, Error> {
+        match entry {
+            Transaction::Deposit { .. } | Transaction::Transfer { .. } => Ok(disputed),
+            Transaction::Withdrawal { .. } => {
+                Ok(disputed.checked_neg().ok_or(balance::Error::Overflow)?)
+            }
+            _ => Err(Error::NotDisputable(*entry.tx())),
+        }
+    }
+
+    fn charge_back_effect(&self, _entry: &Transaction) -> ChargeBackEffect {
+        ChargeBackEffect::LockAccount
+    }
+}
+
+/// Only deposits may be disputed. Otherwise the same as [`StandardPolicy`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DepositOnlyPolicy;
+
+impl DisputePolicy for DepositOnlyPolicy {
+    fn is_disputable(&self, entry: &Transaction) -> bool {
+        matches!(entry, Transaction::Deposit { .. })
+    }
+
+    fn hold_amount(&self, entry: &Transaction, disputed: Amount) -> Result<Amount, Error> {
+        StandardPolicy.hold_amount(entry, disputed)
+    }
+
+    fn charge_back_effect(&self, entry: &Transaction) -> ChargeBackEffect {
+        StandardPolicy.charge_back_effect(entry)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{
+        AdminAction, Error, Ledger, Transaction,
+        amount::Amount,
+        currency::Currency,
+        policy::{ChargeBackEffect, DepositOnlyPolicy, DisputePolicy, StandardPolicy},
+    };
+
+    fn seeded(ledger: Ledger) -> Result<Ledger> {
+        let mut ledger = ledger;
+        ledger.process_transaction(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: "100".parse()?,
+            currency: Currency::NONE,
+        })?;
+        ledger.process_transaction(Transaction::Withdrawal {
+            client: 0,
+            tx: 2,
+            amount: "40".parse()?,
+            currency: Currency::NONE,
+        })?;
+        Ok(ledger)
+    }
+
+    #[test]
+    fn withdrawals_not_disputable() -> Result<()> {
+        let mut ledger = seeded(Ledger::new().with_dispute_policy(DepositOnlyPolicy))?;
+
+        assert!(matches!(
+            ledger.process_transaction(Transaction::Dispute {
+                client: 0,
+                tx: 2,
+                amount: None,
+            }),
+            Err(Error::NotDisputable(2))
+        ));
+        ledger.process_transaction(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        assert_eq!(Some("-40".parse()?), ledger.get_available_balance(0));
+
+        Ok(())
+    }
+
+    /// Holds a flat amount on any dispute and leaves the account open on charge back
+    struct Lenient;
+
+    impl DisputePolicy for Lenient {
+        fn is_disputable(&self, entry: &Transaction) -> bool {
+            StandardPolicy.is_disputable(entry)
+        }
+
+        fn hold_amount(&self, _entry: &Transaction, _disputed: Amount) -> Result<Amount, Error> {
+            Ok("25".parse().expect("valid amount literal"))
+        }
+
+        fn charge_back_effect(&self, _entry: &Transaction) -> ChargeBackEffect {
+            ChargeBackEffect::KeepOpen
+        }
+    }
+
+    #[test]
+    fn custom_policy() -> Result<()> {
+        let mut ledger = seeded(Ledger::new().with_dispute_policy(Lenient))?;
+
+        ledger.process_transaction(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        assert_eq!(Some("35".parse()?), ledger.get_available_balance(0));
+
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        assert!(!ledger.get_client_snapshots()[0].locked);
+        assert_eq!(Some("35".parse()?), ledger.get_available_balance(0));
+
+        Ok(())
+    }
+
+    /// Lets anything at all be disputed
+    struct Anything;
+
+    impl DisputePolicy for Anything {
+        fn is_disputable(&self, _entry: &Transaction) -> bool {
+            true
+        }
+
+        fn hold_amount(&self, _entry: &Transaction, disputed: Amount) -> Result<Amount, Error> {
+            Ok(disputed)
+        }
+
+        fn charge_back_effect(&self, _entry: &Transaction) -> ChargeBackEffect {
+            ChargeBackEffect::KeepOpen
+        }
+    }
+
+    #[test]
+    fn entries_without_a_currency_are_not_disputable() -> Result<()> {
+        let mut ledger = seeded(Ledger::new().with_dispute_policy(Anything))?;
+        for (tx, action) in [(3, AdminAction::Freeze), (4, AdminAction::Unlock)] {
+            ledger.process_transaction(Transaction::Admin {
+                client: 0,
+                tx,
+                action,
+                operator: 1,
+                reason: "review".to_string(),
+            })?;
+        }
+
+        assert!(matches!(
+            ledger.process_transaction(Transaction::Dispute {
+                client: 0,
+                tx: 4,
+                amount: None,
+            }),
+            Err(Error::NotDisputable(4))
+        ));
+        assert_eq!(Some("60".parse()?), ledger.get_available_balance(0));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+//! Sub module for representment. A merchant may contest a charge back with the card
+//! network and win the funds back, so a charged back entry can be represented. This
+//! restores what the charge back removed from the balance and, when the ledger is
+//! configured to, lifts the freeze the charge back placed on the account. An entry which
+//! was only charged back in part may be represented too.
+
+use crate::ledger::{Client, Error, Ledger, Tx, books::Account};
+
+impl Ledger {
+    /// Lift the freeze on an account once the last charged back entry on it is
+    /// represented. Off by default, in which case the account stays frozen until an
+    /// operator unlocks it
+    pub fn with_representment_unlock(mut self, unlock: bool) -> Self {
+        self.unlock_on_represent = unlock;
+        self
+    }
+
+    /// Reverse the charge back on an entry, restoring the funds which were charged back
+    pub(crate) fn represent(&mut self, key: &(Client, Tx)) -> Result<(), Error> {
+        let policy = self.policy.clone();
+        let mut entry = self.entry(key)?;
+
+        // Only an entry which was charged back may be represented
+        let charged_back = entry.represent()?;
+        let currency = entry.currency()?;
+
+        // The charge back removed the hold placed on what it charged back
+        let restored = policy.hold_amount(&entry.t, charged_back)?;
+        let holds = self.split_hold(&entry.t, restored)?;
+
+        for (holder, restored) in holds {
+            self.balance_mut(holder, currency)
+                .reverse_charge_back(restored)?;
+            if restored.is_positive() {
+                let (debit, credit) = (Account::Cash, Account::Client(holder));
+                self.post(*key, currency, debit, credit, restored)?;
+            } else if let Some(taken) = restored.checked_neg().filter(|a| a.is_positive()) {
+                let (debit, credit) = (Account::Client(holder), Account::ChargeBackLosses);
+                self.post(*key, currency, debit, credit, taken)?;
+            }
+        }
+
+        self.transactions.update(&entry)?;
+
+        if let Some(count) = self.charge_backs.get_mut(&key.0) {
+            *count -= 1;
+            if *count == 0 {
+                self.charge_backs.remove(&key.0);
+            }
+        }
+
+        if self.unlock_on_represent
+            && self.locked.contains(&key.0)
+            && !self.charge_backs.contains_key(&key.0)
+        {
+            self.unlock_client(key.0);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{
+        AdminAction, Error, Ledger, Transaction, TxStatus, amount::Amount, currency::Currency,
+    };
+
+    fn amount(s: &str) -> Amount {
+        s.parse().expect("valid amount literal")
+    }
+
+    /// Two deposits which are both charged back. An operator lifted the freeze placed by
+    /// the first charge back so the second could go through
+    fn charged_back(ledger: Ledger) -> Result<Ledger> {
+        let mut ledger = ledger;
+        for tx in [1, 2] {
+            ledger.process_transaction(Transaction::Deposit {
+                client: 0,
+                tx,
+                amount: amount("10"),
+                currency: Currency::NONE,
+            })?;
+        }
+        for tx in [1, 2] {
+            ledger.process_transaction(Transaction::Dispute {
+                client: 0,
+                tx,
+                amount: None,
+            })?;
+        }
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::Admin {
+            client: 0,
+            tx: 3,
+            action: AdminAction::Unlock,
+            operator: 1,
+            reason: "appeal".to_string(),
+        })?;
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 0,
+            tx: 2,
+            amount: None,
+        })?;
+        Ok(ledger)
+    }
+
+    #[test]
+    fn representment_restores_funds() -> Result<()> {
+        let mut ledger = charged_back(Ledger::new())?;
+
+        ledger.process_transaction(Transaction::Represent { client: 0, tx: 1 })?;
+        assert_eq!(Some(amount("10")), ledger.get_available_balance(0));
+        assert_eq!(
+            TxStatus::Represented,
+            ledger
+                .get_entry(0, 1)?
+                .expect("entry was registered")
+                .status()
+        );
+
+        // The account stays frozen unless asked otherwise, and nothing is represented twice
+        assert!(ledger.get_client_snapshot(0)[0].locked);
+        assert!(matches!(
+            ledger.process_transaction(Transaction::Represent { client: 0, tx: 1 }),
+            Err(Error::UnexpectedTxStatus(TxStatus::Represented))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn last_representment_unlocks() -> Result<()> {
+        let mut ledger = charged_back(Ledger::new().with_representment_unlock(true))?;
+
+        // Another charge back still stands
+        ledger.process_transaction(Transaction::Represent { client: 0, tx: 1 })?;
+        assert!(ledger.get_client_snapshot(0)[0].locked);
+
+        ledger.process_transaction(Transaction::Represent { client: 0, tx: 2 })?;
+        let snapshot = ledger.get_client_snapshot(0)[0];
+        assert!(!snapshot.locked);
+        assert_eq!(amount("20"), snapshot.total);
+
+        Ok(())
+    }
+
+    #[test]
+    fn partial_charge_back_is_represented() -> Result<()> {
+        let mut ledger = Ledger::new().with_representment_unlock(true);
+        ledger.process_transaction(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: amount("100"),
+            currency: Currency::NONE,
+        })?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: Some(amount("20")),
+        })?;
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+
+        // The rest of the entry may still be disputed, so it goes back to being active
+        let entry = ledger.get_entry(0, 1)?.expect("entry was registered");
+        assert_eq!(TxStatus::Active, entry.status());
+        assert!(ledger.get_client_snapshot(0)[0].locked);
+
+        ledger.process_transaction(Transaction::Represent { client: 0, tx: 1 })?;
+        let snapshot = ledger.get_client_snapshot(0)[0];
+        assert!(!snapshot.locked);
+        assert_eq!(amount("100"), snapshot.total);
+
+        let entry = ledger.get_entry(0, 1)?.expect("entry was registered");
+        assert_eq!(TxStatus::Active, entry.status());
+        assert_eq!(Amount::ZERO, entry.charged_back());
+        assert!(matches!(
+            ledger.process_transaction(Transaction::Represent { client: 0, tx: 1 }),
+            Err(Error::UnexpectedTxStatus(TxStatus::Active))
+        ));
+
+        Ok(())
+    }
+}
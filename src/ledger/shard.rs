@@ -0,0 +1,388 @@
+//! Sub module for processing transactions across several threads. Every rule within the
+//! ledger is scoped to a single client, so clients are split between worker ledgers which
+//! each run on their own thread. A client always lands on the same worker and each worker
+//! reads from a FIFO channel, so the order of transactions for a client is preserved.
+//!
+//! Transfers are the one rule which spans two clients, so a ledger holding transfers can't
+//! be split and transfers can't be submitted. Refusing them outright keeps the balances the
+//! same however many workers there are.
+
+use std::{
+    sync::{
+        Arc,
+        mpsc::{SyncSender, sync_channel},
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::ledger::{Client, Error, Ledger, Timestamp, Transaction, books::Books};
+
+/// Transactions are handed to workers in batches to keep channel overhead out of the way
+const BATCH_SIZE: usize = 512;
+
+/// Batches which may be queued for a worker before submitting blocks
+const CHANNEL_BOUND: usize = 16;
+
+type Batch<T> = Vec<(T, Transaction, Option<Timestamp>, Option<Arc<str>>)>;
+
+/// Rejected transactions along with the tag they were submitted with
+type Rejected<T> = Vec<(T, Error)>;
+
+pub struct ShardedLedger<T> {
+    senders: Vec<SyncSender<Batch<T>>>,
+    workers: Vec<JoinHandle<(Ledger, Rejected<T>)>>,
+
+    /// Transactions waiting to be sent to each worker
+    pending: Vec<Batch<T>>,
+}
+
+impl<T: Send + 'static> ShardedLedger<T> {
+    /// Split `ledger` between `shards` worker threads. Every transaction is submitted with
+    /// a tag of the caller's choosing, which is handed back if the transaction is rejected.
+    /// A ledger which holds a transfer can't be split.
+    pub fn new(ledger: Ledger, shards: usize) -> Result<Self, Error> {
+        let shards = shards.max(1);
+
+        let mut senders = Vec::with_capacity(shards);
+        let mut workers = Vec::with_capacity(shards);
+
+        for mut ledger in ledger.into_shards(shards)? {
+            let (tx, rx) = sync_channel::<Batch<T>>(CHANNEL_BOUND);
+            senders.push(tx);
+            workers.push(thread::spawn(move || {
+                let mut rejected = Vec::new();
+                for (tag, t, at, source) in rx.into_iter().flatten() {
+                    if let Err(e) = ledger.process_transaction_from(t, at, source.as_deref()) {
+                        rejected.push((tag, e));
+                    }
+                }
+                (ledger, rejected)
+            }));
+        }
+
+        Ok(ShardedLedger {
+            senders,
+            workers,
+            pending: (0..shards)
+                .map(|_| Vec::with_capacity(BATCH_SIZE))
+                .collect(),
+        })
+    }
+
+    /// Queue a transaction on the worker which owns its client
+    pub fn submit(&mut self, tag: T, t: Transaction) -> Result<(), Error> {
+        self.submit_at(tag, t, None)
+    }
+
+    /// Queue a transaction which happened at `at` on the worker which owns its client
+    pub fn submit_at(
+        &mut self,
+        tag: T,
+        t: Transaction,
+        at: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        self.submit_from(tag, t, at, None)
+    }
+
+    /// Queue a transaction which happened at `at` and was read from `source` on the worker
+    /// which owns its client. Transfers are refused without being queued
+    pub fn submit_from(
+        &mut self,
+        tag: T,
+        t: Transaction,
+        at: Option<Timestamp>,
+        source: Option<Arc<str>>,
+    ) -> Result<(), Error> {
+        if let Transaction::Transfer { tx, .. } = t {
+            Err(Error::ShardedTransfer(tx))?
+        }
+
+        let shard = shard_of(*t.client(), self.senders.len());
+        self.pending[shard].push((tag, t, at, source));
+
+        if self.pending[shard].len() >= BATCH_SIZE {
+            self.flush(shard);
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self, shard: usize) {
+        let batch = std::mem::replace(&mut self.pending[shard], Vec::with_capacity(BATCH_SIZE));
+        self.senders[shard]
+            .send(batch)
+            .expect("Workers run until the senders are dropped");
+    }
+
+    /// Wait for every queued transaction to be processed and merge the workers back into
+    /// a single ledger. Rejections are returned grouped by worker.
+    ///
+    /// Each worker's clock only moves with its own clients' transactions, so a dispute
+    /// deadline passed by another worker's clock is only settled here once they're merged.
+    pub fn finish(mut self) -> Result<(Ledger, Rejected<T>), Error> {
+        for shard in 0..self.senders.len() {
+            self.flush(shard);
+        }
+
+        // Closing the channels lets each worker run to completion
+        drop(self.senders);
+
+        let mut shards = Vec::with_capacity(self.workers.len());
+        let mut rejected = Vec::new();
+        for worker in self.workers {
+            let (ledger, mut r) = worker.join().expect("Worker should not panic");
+            shards.push(ledger);
+            rejected.append(&mut r);
+        }
+
+        Ok((Ledger::merge(shards)?, rejected))
+    }
+}
+
+fn shard_of(client: Client, shards: usize) -> usize {
+    usize::from(client) % shards
+}
+
+impl Ledger {
+    /// Split the ledger into `shards` ledgers with each client owned by exactly one of them
+    fn into_shards(mut self, shards: usize) -> Result<Vec<Ledger>, Error> {
+        let mut ledgers = Vec::with_capacity(shards);
+        for part in 0..shards {
+            ledgers.push(Ledger {
+                policy: self.policy.clone(),
+                window: self.window,
+                limits: self.limits.clone(),
+                fees: self.fees.clone(),
+                sources: self.sources.clone(),
+                unlock_on_represent: self.unlock_on_represent,
+                books: self
+                    .books
+                    .as_mut()
+                    .map(|books| books.split_off(|client| shard_of(client, shards) == part)),
+                clock: self.clock,
+                transactions: self.transactions.empty(Some(part))?,
+                ..Ledger::new()
+            });
+        }
+
+        for (client, tx) in self.fee_txs {
+            ledgers[shard_of(client, shards)].fee_txs.insert(client, tx);
+        }
+
+        for (client, count) in self.charge_backs {
+            ledgers[shard_of(client, shards)]
+                .charge_backs
+                .insert(client, count);
+        }
+
+        for entry in self.transactions.entries() {
+            let entry = entry?;
+            if let Transaction::Transfer { tx, .. } = entry.t {
+                Err(Error::ShardedTransfer(tx))?
+            }
+            ledgers[shard_of(*entry.t.client(), shards)]
+                .transactions
+                .insert(entry)?;
+        }
+
+        for (key, balance) in self.balance {
+            ledgers[shard_of(key.0, shards)]
+                .balance
+                .insert(key, balance);
+        }
+
+        for client in self.locked {
+            ledgers[shard_of(client, shards)].locked.insert(client);
+        }
+
+        for deadline in self.deadlines {
+            ledgers[shard_of(deadline.1, shards)]
+                .deadlines
+                .insert(deadline);
+        }
+
+        Ok(ledgers)
+    }
+
+    /// Combine ledgers which don't share any clients
+    fn merge(shards: Vec<Ledger>) -> Result<Ledger, Error> {
+        let mut merged = Ledger::new();
+        if let Some(first) = shards.first() {
+            merged.policy = first.policy.clone();
+            merged.window = first.window;
+            merged.limits = first.limits.clone();
+            merged.fees = first.fees.clone();
+            merged.unlock_on_represent = first.unlock_on_represent;
+            merged.books = first.books.as_ref().map(|_| Books::new());
+            merged.transactions = first.transactions.empty(None)?;
+        }
+        let clock = shards.iter().filter_map(|s| s.clock).max();
+
+        for shard in shards {
+            for entry in shard.transactions.entries() {
+                merged.transactions.insert(entry?)?;
+            }
+            merged.balance.extend(shard.balance);
+            merged.locked.extend(shard.locked);
+            merged.fee_txs.extend(shard.fee_txs);
+            merged.charge_backs.extend(shard.charge_backs);
+            merged.sources.extend(shard.sources);
+            merged.deadlines.extend(shard.deadlines);
+            if let (Some(books), Some(postings)) = (merged.books.as_mut(), shard.books) {
+                books
+                    .extend(postings)
+                    .expect("Totals of the merged books are representable");
+            }
+        }
+
+        // Bring every client up to the latest time any worker saw
+        if let Some(clock) = clock {
+            merged.advance_clock(clock)?;
+        }
+
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{Error, Ledger, Transaction, currency::Currency, shard::ShardedLedger};
+
+    fn deposit(client: u16, tx: u32, amount: &str) -> Transaction {
+        Transaction::Deposit {
+            client,
+            tx,
+            amount: amount.parse().unwrap(),
+            currency: Currency::NONE,
+        }
+    }
+
+    #[test]
+    fn matches_single_threaded_ledger() -> Result<()> {
+        let mut transactions = Vec::new();
+        for client in 0..20u16 {
+            let base = u32::from(client) * 10;
+            transactions.push(deposit(client, base + 1, "100"));
+            transactions.push(Transaction::Withdrawal {
+                client,
+                tx: base + 2,
+                amount: "30".parse()?,
+                currency: Currency::NONE,
+            });
+            transactions.push(Transaction::Dispute {
+                client,
+                tx: base + 1,
+                amount: None,
+            });
+            if client % 2 == 0 {
+                transactions.push(Transaction::ChargeBack {
+                    client,
+                    tx: base + 1,
+                    amount: None,
+                });
+            }
+            // Rejected on the duplicate id, or on the frozen account
+            transactions.push(deposit(client, base + 1, "5"));
+        }
+
+        let mut single = Ledger::new();
+        let mut single_rejected = 0;
+        for t in transactions.iter() {
+            if single.process_transaction(t.clone()).is_err() {
+                single_rejected += 1;
+            }
+        }
+
+        let mut sharded = ShardedLedger::new(Ledger::new(), 4)?;
+        for (i, t) in transactions.iter().enumerate() {
+            sharded.submit(i, t.clone())?;
+        }
+        let (merged, rejected) = sharded.finish()?;
+
+        assert_eq!(single_rejected, rejected.len());
+        assert!(rejected.iter().all(|(i, e)| matches!(
+            transactions[*i],
+            Transaction::Deposit { .. }
+        ) && matches!(
+            e,
+            Error::DuplicateTransaction(_) | Error::FrozenAccountError(_)
+        )));
+
+        let mut expected = single.get_client_snapshots();
+        expected.sort_by_key(|s| s.client);
+        let mut actual = merged.get_client_snapshots();
+        actual.sort_by_key(|s| s.client);
+        assert_eq!(expected, actual);
+
+        Ok(())
+    }
+
+    #[test]
+    fn existing_state_is_split_between_workers() -> Result<()> {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(deposit(1, 1, "10"))?;
+        ledger.process_transaction(deposit(2, 2, "10"))?;
+
+        let mut sharded = ShardedLedger::new(ledger, 2)?;
+        sharded.submit(
+            (),
+            Transaction::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+        )?;
+        sharded.submit(
+            (),
+            Transaction::Dispute {
+                client: 2,
+                tx: 2,
+                amount: None,
+            },
+        )?;
+        let (merged, rejected) = sharded.finish()?;
+
+        assert!(rejected.is_empty());
+        assert!(
+            merged
+                .get_client_snapshots()
+                .iter()
+                .all(|s| s.held == "10".parse().unwrap())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn transfers_are_refused() -> Result<()> {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(deposit(1, 1, "10"))?;
+        let transfer = Transaction::Transfer {
+            from: 1,
+            tx: 2,
+            to: 2,
+            amount: "5".parse()?,
+            currency: Currency::NONE,
+        };
+
+        // Refused whether or not both clients land on the same worker
+        let mut sharded = ShardedLedger::new(ledger, 1)?;
+        assert!(matches!(
+            sharded.submit((), transfer.clone()),
+            Err(Error::ShardedTransfer(2))
+        ));
+        let (mut ledger, rejected) = sharded.finish()?;
+        assert!(rejected.is_empty());
+
+        // Nor can a ledger holding a transfer be split
+        ledger.process_transaction(transfer)?;
+        assert!(matches!(
+            ShardedLedger::<()>::new(ledger, 2),
+            Err(Error::ShardedTransfer(2))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+//! Sub module for choosing which snapshots are reported and in what order. Snapshots are
+//! ordered by client by default so the output of two runs over the same input can be diffed.
+
+use std::cmp::Reverse;
+
+use crate::ledger::{Ledger, amount::Amount, balance::BalanceSnapshot};
+
+/// Order snapshots are reported in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotOrder {
+    /// By client, then currency
+    #[default]
+    Client,
+    /// Largest total first
+    Total,
+    /// Largest available balance first
+    Available,
+    /// Largest held balance first
+    Held,
+}
+
+/// Snapshots to keep, the rest are left out of the report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFilter {
+    /// Only frozen accounts
+    Locked,
+    /// Only accounts with funds held by an open dispute
+    Held,
+}
+
+impl SnapshotFilter {
+    fn keep(&self, snapshot: &BalanceSnapshot) -> bool {
+        match self {
+            SnapshotFilter::Locked => snapshot.locked,
+            SnapshotFilter::Held => snapshot.held != Amount::ZERO,
+        }
+    }
+}
+
+impl Ledger {
+    /// Snapshots of every client in the given order, keeping only those which pass the
+    /// filter if one is given. Ties are broken by client then currency
+    pub fn get_client_snapshots_by(
+        &self,
+        order: SnapshotOrder,
+        filter: Option<SnapshotFilter>,
+    ) -> Vec<BalanceSnapshot> {
+        let mut snapshots: Vec<BalanceSnapshot> = self
+            .get_client_snapshots()
+            .into_iter()
+            .filter(|s| filter.is_none_or(|f| f.keep(s)))
+            .collect();
+
+        // Snapshots are already ordered by client, and the sort is stable
+        match order {
+            SnapshotOrder::Client => (),
+            SnapshotOrder::Total => snapshots.sort_by_key(|s| Reverse(s.total)),
+            SnapshotOrder::Available => snapshots.sort_by_key(|s| Reverse(s.available)),
+            SnapshotOrder::Held => snapshots.sort_by_key(|s| Reverse(s.held)),
+        }
+
+        snapshots
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{
+        Ledger, Transaction,
+        amount::Amount,
+        currency::Currency,
+        snapshot::{SnapshotFilter, SnapshotOrder},
+    };
+
+    fn amount(s: &str) -> Amount {
+        s.parse().expect("valid amount literal")
+    }
+
+    #[test]
+    fn order_and_filter_snapshots() -> Result<()> {
+        let mut ledger = Ledger::new();
+        for (client, tx, deposit) in [(3, 1, "5"), (1, 2, "20"), (2, 3, "10"), (1, 4, "1")] {
+            ledger.process_transaction(Transaction::Deposit {
+                client,
+                tx,
+                amount: amount(deposit),
+                currency: Currency::NONE,
+            })?;
+        }
+        ledger.process_transaction(Transaction::Dispute {
+            client: 2,
+            tx: 3,
+            amount: None,
+        })?;
+
+        let clients = |order, filter| -> Vec<_> {
+            ledger
+                .get_client_snapshots_by(order, filter)
+                .iter()
+                .map(|s| s.client)
+                .collect()
+        };
+
+        assert_eq!(vec![1, 2, 3], clients(SnapshotOrder::Client, None));
+        assert_eq!(vec![1, 2, 3], clients(SnapshotOrder::Total, None));
+        assert_eq!(vec![1, 3, 2], clients(SnapshotOrder::Available, None));
+        assert_eq!(
+            vec![2],
+            clients(SnapshotOrder::Client, Some(SnapshotFilter::Held))
+        );
+        assert!(clients(SnapshotOrder::Client, Some(SnapshotFilter::Locked)).is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,461 @@
+//! Sub module for saving the full state of a ledger to disk and restoring it on a later run.
+//! The state is written as versioned JSON so the format can evolve without misreading old files.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use serde::{
+    Deserializer, Serialize, Serializer,
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    ser::Error as _,
+};
+use thiserror::Error;
+
+use crate::ledger::{
+    Client, Entry, Ledger, Timestamp, Tx, TxStatus,
+    amount::Amount,
+    balance::{self, Balance},
+    books::{Books, Posting},
+    store::{self, TransactionStore},
+};
+
+/// Version written into every saved state. Bump this whenever the layout changes
+///
+/// Version 2 added partial disputes, fees and the input each entry was read from. Version
+/// 1 states are still read, with every dispute taken to be on the whole entry
+pub const STATE_VERSION: u32 = 2;
+
+#[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    #[error("Unsupported state version: {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Duplicate transaction in saved state: client {0} tx {1}")]
+    DuplicateTransaction(Client, Tx),
+
+    #[error("I/O Error: {0}")]
+    IOError(#[from] io::Error),
+
+    #[error("State Serialization Error: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    StoreError(#[from] store::Error),
+
+    #[error("Saved postings can't be totalled: {0}")]
+    BooksError(balance::Error),
+}
+
+/// The on disk layout of a ledger. The index into the transactions is rebuilt on load.
+/// Entries are written straight out of the store rather than gathered in memory first,
+/// and are read back straight into the store by [`StateSeed`]
+#[derive(Serialize)]
+struct State<E, P> {
+    version: u32,
+    transactions: E,
+    balances: Vec<Balance>,
+    locked: Vec<Client>,
+
+    /// Absent from states saved before timestamps were tracked
+    #[serde(default)]
+    clock: Option<Timestamp>,
+
+    /// The id given to each client's most recent fee, so later fees don't reuse it
+    fee_txs: Vec<(Client, Tx)>,
+
+    /// Only present when double-entry books are kept. Account totals are rebuilt on load
+    #[serde(skip_serializing_if = "Option::is_none")]
+    postings: Option<P>,
+}
+
+/// Reads a saved state into a ledger, handing each entry to the ledger's store as soon as
+/// it's parsed so the entries are never all held in memory at once. Failures which aren't
+/// about the JSON itself are kept aside so they can be reported as they are
+struct StateSeed<'a> {
+    ledger: &'a mut Ledger,
+    failed: Option<Error>,
+}
+
+impl StateSeed<'_> {
+    /// Keep the failure and hand serde an error to stop on
+    fn fail<E: de::Error>(&mut self, error: Error) -> E {
+        let e = E::custom(&error);
+        self.failed = Some(error);
+        e
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for &mut StateSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for &mut StateSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a saved ledger state")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        // The version is always written first so a newer layout is reported as such rather
+        // than as a serialization error part way through the entries
+        let mut version = None;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "version" => {
+                    let v: u32 = map.next_value()?;
+                    if !(1..=STATE_VERSION).contains(&v) {
+                        return Err(self.fail(Error::UnsupportedVersion(v)));
+                    }
+                    version = Some(v);
+                }
+                "transactions" => map.next_value_seed(EntriesSeed(&mut *self))?,
+                "balances" => {
+                    for balance in map.next_value::<Vec<Balance>>()? {
+                        self.ledger
+                            .balance
+                            .insert((balance.client(), balance.currency()), balance);
+                    }
+                }
+                "locked" => {
+                    self.ledger.locked = map.next_value::<Vec<Client>>()?.into_iter().collect();
+                }
+                "clock" => self.ledger.clock = map.next_value()?,
+                // Fees in version 1 took their ids counting down from the top of the id
+                // range. Later fees count up from 1 past any of them instead
+                "fee_txs" if version == Some(1) => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+                "fee_txs" => {
+                    self.ledger.fee_txs =
+                        map.next_value::<Vec<(Client, Tx)>>()?.into_iter().collect();
+                }
+                "postings" => {
+                    if let Some(postings) = map.next_value::<Option<Vec<Posting>>>()? {
+                        match Books::from_postings(postings) {
+                            Ok(books) => self.ledger.books = Some(books),
+                            Err(e) => return Err(self.fail(Error::BooksError(e))),
+                        }
+                    }
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        match version {
+            Some(_) => Ok(()),
+            None => Err(de::Error::missing_field("version")),
+        }
+    }
+}
+
+/// Reads the saved entries one at a time into the ledger's store
+struct EntriesSeed<'a, 'b>(&'a mut StateSeed<'b>);
+
+impl<'de> DeserializeSeed<'de> for EntriesSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for EntriesSeed<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of entries")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(entry) = seq.next_element::<Entry>()? {
+            if let Err(e) = self.0.ledger.restore_entry(entry) {
+                return Err(self.0.fail(e));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes every entry in a store
+struct StoredEntries<'a>(&'a dyn TransactionStore);
+
+impl Serialize for StoredEntries<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut failed = None;
+        let entries = self.0.entries().map_while(|e| match e {
+            Ok(e) => Some(e),
+            Err(e) => {
+                failed = Some(e);
+                None
+            }
+        });
+        let ok = serializer.collect_seq(entries);
+
+        match failed {
+            Some(e) => Err(S::Error::custom(e)),
+            None => ok,
+        }
+    }
+}
+
+impl Ledger {
+    /// Write every entry, balance, hold and lock within the ledger
+    pub fn save(&self, writer: impl Write) -> Result<(), Error> {
+        let mut locked: Vec<Client> = self.locked.iter().copied().collect();
+        locked.sort();
+        let mut fee_txs: Vec<(Client, Tx)> = self.fee_txs.iter().map(|(c, t)| (*c, *t)).collect();
+        fee_txs.sort();
+
+        let state = State {
+            version: STATE_VERSION,
+            transactions: StoredEntries(self.transactions.as_ref()),
+            balances: self.balance.values().cloned().collect(),
+            locked,
+            clock: self.clock,
+            fee_txs,
+            postings: self.books.as_ref().map(|books| books.postings()),
+        };
+
+        let mut writer = BufWriter::new(writer);
+        serde_json::to_writer(&mut writer, &state)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Restore a ledger which was written by [`Ledger::save`], keeping its entries in
+    /// `store`. The store should be empty
+    pub fn load(
+        reader: impl Read,
+        store: impl TransactionStore + 'static,
+    ) -> Result<Ledger, Error> {
+        let mut ledger = Ledger::new();
+        ledger.transactions = Box::new(store);
+
+        let mut seed = StateSeed {
+            ledger: &mut ledger,
+            failed: None,
+        };
+        let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+        let parsed = (&mut seed).deserialize(&mut deserializer);
+
+        if let Some(e) = seed.failed {
+            Err(e)?
+        }
+        parsed?;
+        deserializer.end()?;
+
+        Ok(ledger)
+    }
+
+    /// Register an entry read from a saved state
+    fn restore_entry(&mut self, mut entry: Entry) -> Result<(), Error> {
+        let key = entry.t.key();
+        if self.transactions.contains(&entry.key()) {
+            Err(Error::DuplicateTransaction(key.0, key.1))?
+        }
+        entry.source = entry.source.map(|source| self.intern_source(&source));
+
+        // Version 1 states only knew of disputes on the whole entry
+        let whole = entry.t.amount().unwrap_or(Amount::ZERO);
+        if entry.status == TxStatus::Disputed && entry.open == Amount::ZERO {
+            entry.disputed = whole;
+            entry.open = whole;
+        }
+        if entry.status == TxStatus::ChargedBack && entry.charged_back == Amount::ZERO {
+            entry.disputed = whole;
+            entry.charged_back = whole;
+        }
+
+        // Open disputes pick their deadlines back up
+        if let (TxStatus::Disputed, Some(deadline)) = (entry.status, entry.deadline) {
+            self.deadlines.insert((deadline, key.0, key.1));
+        }
+        if entry.stands_charged_back() {
+            *self.charge_backs.entry(key.0).or_default() += 1;
+        }
+
+        self.transactions.insert(entry)?;
+
+        Ok(())
+    }
+
+    /// Save the ledger to a file. The state is written beside the destination first
+    /// and moved into place so a crash never leaves a partially written file behind
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let f = File::create(&tmp)?;
+        self.save(&f)?;
+        f.sync_all()?;
+
+        fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Restore a ledger from a file written by [`Ledger::save_to_file`], keeping its
+    /// entries in `store`
+    pub fn load_from_file(
+        path: impl AsRef<Path>,
+        store: impl TransactionStore + 'static,
+    ) -> Result<Ledger, Error> {
+        Self::load(File::open(path)?, store)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{
+        EntryKey, Ledger, Transaction,
+        amount::Amount,
+        currency::Currency,
+        fees::{FeeRule, FeeSchedule, FeeTrigger},
+        state::{Error, STATE_VERSION},
+        store::MemoryStore,
+    };
+
+    #[test]
+    fn dispute_after_restore() -> Result<()> {
+        let mut ledger = Ledger::new();
+        ledger.process_transaction(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: "100".parse()?,
+            currency: Currency::NONE,
+        })?;
+        ledger.process_transaction(Transaction::Deposit {
+            client: 1,
+            tx: 2,
+            amount: "10".parse()?,
+            currency: Currency::NONE,
+        })?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 2,
+            amount: None,
+        })?;
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 1,
+            tx: 2,
+            amount: None,
+        })?;
+
+        let mut saved = Vec::new();
+        ledger.save(&mut saved)?;
+
+        let mut restored = Ledger::load(saved.as_slice(), MemoryStore::default())?;
+
+        // Yesterday's deposit can still be disputed
+        restored.process_transaction(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            amount: None,
+        })?;
+        assert_eq!(Some("0".parse()?), restored.get_available_balance(0));
+
+        // Duplicates of restored transactions are still caught
+        assert!(
+            restored
+                .process_transaction(Transaction::Deposit {
+                    client: 0,
+                    tx: 1,
+                    amount: "1".parse()?,
+                    currency: Currency::NONE,
+                })
+                .is_err()
+        );
+
+        // Locks survive
+        assert!(
+            restored
+                .process_transaction(Transaction::Deposit {
+                    client: 1,
+                    tx: 3,
+                    amount: "1".parse()?,
+                    currency: Currency::NONE,
+                })
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_version_one() -> Result<()> {
+        let state = r#"{"version":1,"transactions":[
+            {"t":{"type":"deposit","client":1,"tx":1,"amount":"100.0000","currency":"XXX"},"status":"Disputed"},
+            {"t":{"type":"withdrawal","client":1,"tx":2,"amount":"10.0000","currency":"XXX"},"status":"Active"},
+            {"t":{"type":"fee","client":1,"tx":4294967295,"amount":"1.0000","currency":"XXX","charged_for":2},"status":"Active"}
+        ],"balances":[{"client":1,"currency":"XXX","total":"89.0000","holds":{"1":"100.0000"},"locked":false,"fees":"1.0000"}],
+        "locked":[],"clock":null,"fee_txs":[[1,4294967295]]}"#;
+        let mut ledger = Ledger::load(state.as_bytes(), MemoryStore::default())?;
+
+        // The dispute is on the whole deposit
+        let entry = ledger.get_entry(1, 1)?.expect("deposit was restored");
+        assert_eq!("100".parse::<Amount>()?, entry.disputed());
+        ledger.process_transaction(Transaction::Resolve {
+            client: 1,
+            tx: 1,
+            amount: Some("40".parse()?),
+        })?;
+        assert_eq!(Some("29".parse()?), ledger.get_available_balance(1));
+
+        // Later fees are numbered in their own right rather than down from the old ones
+        let mut fees = FeeSchedule::new();
+        fees.push(FeeRule {
+            on: FeeTrigger::Withdrawal,
+            min: Amount::ZERO,
+            max: None,
+            currency: None,
+            fee: "1".parse()?,
+        });
+        let mut ledger = ledger.with_fee_schedule(fees);
+        ledger.process_transaction(Transaction::Withdrawal {
+            client: 1,
+            tx: 3,
+            amount: "1".parse()?,
+            currency: Currency::NONE,
+        })?;
+        let history = ledger.get_client_history(1, 0, usize::MAX)?;
+        assert_eq!(
+            Some(EntryKey::Fee(1, 1)),
+            history.last().map(|entry| entry.key())
+        );
+
+        // Saving writes the current version
+        let mut saved = Vec::new();
+        ledger.save(&mut saved)?;
+        assert!(saved.starts_with(format!(r#"{{"version":{},"#, STATE_VERSION).as_bytes()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unknown_version() {
+        let state = format!(r#"{{"version":{},"accounts":[]}}"#, STATE_VERSION + 1);
+
+        assert!(matches!(
+            Ledger::load(state.as_bytes(), MemoryStore::default()),
+            Err(Error::UnsupportedVersion(_))
+        ));
+    }
+}
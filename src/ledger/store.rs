@@ -0,0 +1,131 @@
+//! Sub module for where the ledger keeps its entries. Every deposit, withdrawal and
+//! administrative transaction is kept so that it can be disputed later, which on a long
+//! history is far more than the balances themselves. The ledger reaches its entries through
+//! a [`TransactionStore`] so they can be kept in memory or on disk.
+
+use std::{collections::HashMap, io};
+
+use thiserror::Error;
+
+use crate::ledger::{Entry, EntryKey};
+
+pub mod disk;
+pub use disk::DiskStore;
+
+#[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    #[error("Corrupt store record at byte {0}")]
+    CorruptRecord(u64),
+
+    #[error("I/O Error: {0}")]
+    IOError(#[from] io::Error),
+
+    #[error("Store Serialization Error: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+/// Every entry in a store, in the order they were inserted
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<Entry, Error>> + 'a>;
+
+/// Keeps the entries of a ledger, keyed by [`Entry::key`]
+pub trait TransactionStore: Send {
+    /// Whether an entry is registered under the key
+    fn contains(&self, key: &EntryKey) -> bool;
+
+    /// A copy of the entry registered under the key
+    fn get(&self, key: &EntryKey) -> Result<Option<Entry>, Error>;
+
+    /// Register a new entry. The ledger never inserts a key which is already registered
+    fn insert(&mut self, entry: Entry) -> Result<(), Error>;
+
+    /// Write back the dispute state of an entry which is already registered, being its
+    /// status, deadline and disputed amounts. The transaction itself never changes
+    fn update(&mut self, entry: &Entry) -> Result<(), Error>;
+
+    /// Write back the dispute state of `updated`, if given, and register `inserted` as a
+    /// single change. When it fails none of it is written, so a transaction which changes
+    /// more than one entry is never left half written
+    fn write(&mut self, updated: Option<&Entry>, inserted: Vec<Entry>) -> Result<(), Error>;
+
+    /// The number of entries registered
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every entry in the order it was inserted
+    fn entries(&self) -> Entries<'_>;
+
+    /// An empty store of the same kind. When the ledger is split between worker threads
+    /// each worker's store is created with its own `part`
+    fn empty(&self, part: Option<usize>) -> Result<Box<dyn TransactionStore>, Error>;
+}
+
+/// Keeps every entry in memory. This is the default store
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    /// Mapping of an entry by key to the index it's written into memory
+    client_tx_to_idx: HashMap<EntryKey, usize>,
+
+    /// All entries in the order they were inserted
+    entries: Vec<Entry>,
+}
+
+impl TransactionStore for MemoryStore {
+    fn contains(&self, key: &EntryKey) -> bool {
+        self.client_tx_to_idx.contains_key(key)
+    }
+
+    fn get(&self, key: &EntryKey) -> Result<Option<Entry>, Error> {
+        Ok(self
+            .client_tx_to_idx
+            .get(key)
+            .map(|idx| self.entries[*idx].clone()))
+    }
+
+    fn insert(&mut self, entry: Entry) -> Result<(), Error> {
+        self.client_tx_to_idx
+            .insert(entry.key(), self.entries.len());
+        self.entries.push(entry);
+
+        Ok(())
+    }
+
+    fn update(&mut self, entry: &Entry) -> Result<(), Error> {
+        if let Some(idx) = self.client_tx_to_idx.get(&entry.key()) {
+            let stored = &mut self.entries[*idx];
+            stored.status = entry.status;
+            stored.deadline = entry.deadline;
+            stored.disputed = entry.disputed;
+            stored.open = entry.open;
+            stored.charged_back = entry.charged_back;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, updated: Option<&Entry>, inserted: Vec<Entry>) -> Result<(), Error> {
+        if let Some(entry) = updated {
+            self.update(entry)?;
+        }
+        for entry in inserted {
+            self.insert(entry)?;
+        }
+
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        Box::new(self.entries.iter().cloned().map(Ok))
+    }
+
+    fn empty(&self, _part: Option<usize>) -> Result<Box<dyn TransactionStore>, Error> {
+        Ok(Box::new(MemoryStore::default()))
+    }
+}
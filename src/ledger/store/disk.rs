@@ -0,0 +1,469 @@
+//! A [`TransactionStore`] which keeps entries in a log structured file, with only an index
+//! of where each entry sits held in memory. Transactions never change once written, so each
+//! is appended to the end of the file once. The dispute state of an entry does change, so it
+//! sits in a fixed size header which is overwritten in place.
+//!
+//! Each record is laid out as
+//!
+//! ```text
+//! | length: u32 LE | status: u8 | has deadline: u8 | deadline: u64 LE | disputed: i64 LE | open: i64 LE | charged back: i64 LE | payload: JSON |
+//! ```
+//!
+//! Amounts are written as their number of ten-thousandths. The payload names the input an
+//! entry was read from by its position in a table of inputs the store keeps in memory.
+//!
+//! The file is scratch space for a single run and is removed when the store is dropped. Use
+//! the saved state or the journal to keep the ledger between runs.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ledger::{
+    Entry, EntryKey, Timestamp, Transaction, TxStatus,
+    amount::Amount,
+    store::{Entries, Error, TransactionStore},
+};
+
+/// Size of the length and dispute state which precede every payload
+const HEADER_LEN: u64 = 4 + STATE_LEN as u64;
+
+/// Size of the dispute state within the header
+const STATE_LEN: usize = 34;
+
+/// The part of an entry which never changes. Written from a borrowed transaction
+#[derive(Debug, Serialize, Deserialize)]
+struct Payload<T = Transaction> {
+    t: T,
+    at: Option<Timestamp>,
+
+    /// Position of the entry's input in the store's table of inputs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<u32>,
+}
+
+pub struct DiskStore {
+    /// Path the store was created at. Stores for worker threads sit beside it
+    base: PathBuf,
+
+    /// Path of this store's file
+    path: PathBuf,
+
+    /// Reads only need a shared reference to the store but still move the file's cursor
+    file: RefCell<File>,
+
+    /// Offset of every entry's record
+    index: HashMap<EntryKey, u64>,
+
+    /// Offset just past the last record
+    end: u64,
+
+    /// Every input an entry has been read from, in the order they were first seen
+    sources: Vec<Arc<str>>,
+}
+
+impl DiskStore {
+    /// Create an empty store at `path`, replacing anything already there
+    pub fn create(path: impl AsRef<Path>) -> Result<DiskStore, Error> {
+        let path = path.as_ref().to_path_buf();
+        Self::create_at(path.clone(), path)
+    }
+
+    fn create_at(base: PathBuf, path: PathBuf) -> Result<DiskStore, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+
+        Ok(DiskStore {
+            base,
+            path,
+            file: RefCell::new(file),
+            index: HashMap::new(),
+            end: 0,
+            sources: Vec::new(),
+        })
+    }
+
+    /// Append the record of an entry to `records`
+    fn encode_record(&mut self, entry: &Entry, records: &mut Vec<u8>) -> Result<(), Error> {
+        let source = entry.source.as_ref().map(|source| self.source_idx(source));
+        let payload = serde_json::to_vec(&Payload {
+            t: &entry.t,
+            at: entry.at,
+            source,
+        })?;
+        let payload_len = u32::try_from(payload.len()).expect("Transactions are small");
+
+        records.reserve(HEADER_LEN as usize + payload.len());
+        records.extend_from_slice(&payload_len.to_le_bytes());
+        records.extend_from_slice(&encode_state(entry));
+        records.extend_from_slice(&payload);
+
+        Ok(())
+    }
+
+    /// Position of an input in the table of inputs, adding it if it's new. A store only
+    /// ever sees a handful of inputs
+    fn source_idx(&mut self, source: &Arc<str>) -> u32 {
+        let idx = match self.sources.iter().position(|s| s == source) {
+            Some(idx) => idx,
+            None => {
+                self.sources.push(source.clone());
+                self.sources.len() - 1
+            }
+        };
+        u32::try_from(idx).expect("A store sees few inputs")
+    }
+}
+
+impl Drop for DiskStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The dispute state of an entry as it's laid out in the header
+fn encode_state(entry: &Entry) -> [u8; STATE_LEN] {
+    let mut state = [0u8; STATE_LEN];
+    state[0] = match entry.status {
+        TxStatus::Active => 0,
+        TxStatus::Disputed => 1,
+        TxStatus::Resolved => 2,
+        TxStatus::ChargedBack => 3,
+        TxStatus::Represented => 4,
+    };
+    if let Some(deadline) = entry.deadline {
+        state[1] = 1;
+        state[2..10].copy_from_slice(&deadline.to_le_bytes());
+    }
+    state[10..18].copy_from_slice(&entry.disputed.to_raw().to_le_bytes());
+    state[18..26].copy_from_slice(&entry.open.to_raw().to_le_bytes());
+    state[26..34].copy_from_slice(&entry.charged_back.to_raw().to_le_bytes());
+    state
+}
+
+/// Read the record starting at `offset`, returning the entry and the length of the record
+fn read_record(
+    reader: &mut impl Read,
+    offset: u64,
+    sources: &[Arc<str>],
+) -> Result<(Entry, u64), Error> {
+    let mut header = [0u8; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+
+    let payload_len = u32::from_le_bytes(header[0..4].try_into().expect("4 bytes"));
+    let status = match header[4] {
+        0 => TxStatus::Active,
+        1 => TxStatus::Disputed,
+        2 => TxStatus::Resolved,
+        3 => TxStatus::ChargedBack,
+        4 => TxStatus::Represented,
+        _ => Err(Error::CorruptRecord(offset))?,
+    };
+    let deadline = match header[5] {
+        0 => None,
+        _ => Some(u64::from_le_bytes(
+            header[6..14].try_into().expect("8 bytes"),
+        )),
+    };
+    let disputed = i64::from_le_bytes(header[14..22].try_into().expect("8 bytes"));
+    let open = i64::from_le_bytes(header[22..30].try_into().expect("8 bytes"));
+    let charged_back = i64::from_le_bytes(header[30..38].try_into().expect("8 bytes"));
+
+    let mut payload = vec![0u8; payload_len as usize];
+    reader.read_exact(&mut payload)?;
+    let Payload { t, at, source } =
+        serde_json::from_slice(&payload).map_err(|_| Error::CorruptRecord(offset))?;
+    let source = match source {
+        Some(idx) => Some(
+            sources
+                .get(idx as usize)
+                .ok_or(Error::CorruptRecord(offset))?
+                .clone(),
+        ),
+        None => None,
+    };
+
+    Ok((
+        Entry {
+            t,
+            status,
+            at,
+            deadline,
+            disputed: Amount::from_raw(disputed),
+            open: Amount::from_raw(open),
+            charged_back: Amount::from_raw(charged_back),
+            source,
+        },
+        HEADER_LEN + u64::from(payload_len),
+    ))
+}
+
+impl TransactionStore for DiskStore {
+    fn contains(&self, key: &EntryKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    fn get(&self, key: &EntryKey) -> Result<Option<Entry>, Error> {
+        let Some(&offset) = self.index.get(key) else {
+            return Ok(None);
+        };
+
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        let (entry, _) = read_record(&mut *file, offset, &self.sources)?;
+
+        Ok(Some(entry))
+    }
+
+    fn insert(&mut self, entry: Entry) -> Result<(), Error> {
+        self.write(None, vec![entry])
+    }
+
+    fn update(&mut self, entry: &Entry) -> Result<(), Error> {
+        if let Some(&offset) = self.index.get(&entry.key()) {
+            let file = self.file.get_mut();
+            file.seek(SeekFrom::Start(offset + 4))?;
+            file.write_all(&encode_state(entry))?;
+        }
+
+        Ok(())
+    }
+
+    fn write(&mut self, updated: Option<&Entry>, inserted: Vec<Entry>) -> Result<(), Error> {
+        let mut records = Vec::new();
+        let mut offsets = Vec::with_capacity(inserted.len());
+        for entry in &inserted {
+            offsets.push((entry.key(), self.end + records.len() as u64));
+            self.encode_record(entry, &mut records)?;
+        }
+
+        // New records are only indexed once everything is written. Until then they sit past
+        // the end of the file, where a failed write leaves them to be written over
+        if !records.is_empty() {
+            let file = self.file.get_mut();
+            file.seek(SeekFrom::Start(self.end))?;
+            file.write_all(&records)?;
+        }
+        if let Some(entry) = updated {
+            self.update(entry)?;
+        }
+
+        self.index.extend(offsets);
+        self.end += records.len() as u64;
+
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn entries(&self) -> Entries<'_> {
+        // A separate handle keeps iterating from moving the cursor used by lookups
+        match File::open(&self.path) {
+            Ok(file) => Box::new(DiskEntries {
+                reader: BufReader::new(file),
+                offset: 0,
+                end: self.end,
+                sources: &self.sources,
+            }),
+            Err(e) => Box::new(std::iter::once(Err(e.into()))),
+        }
+    }
+
+    fn empty(&self, part: Option<usize>) -> Result<Box<dyn TransactionStore>, Error> {
+        let path = match part {
+            Some(part) => {
+                let mut path = self.base.as_os_str().to_owned();
+                path.push(format!(".{}", part));
+                PathBuf::from(path)
+            }
+            None => self.base.clone(),
+        };
+
+        Ok(Box::new(DiskStore::create_at(self.base.clone(), path)?))
+    }
+}
+
+/// Reads every record of a store's file from the start
+struct DiskEntries<'a> {
+    reader: BufReader<File>,
+    offset: u64,
+    end: u64,
+    sources: &'a [Arc<str>],
+}
+
+impl Iterator for DiskEntries<'_> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.end {
+            return None;
+        }
+
+        match read_record(&mut self.reader, self.offset, self.sources) {
+            Ok((entry, len)) => {
+                self.offset += len;
+                Some(Ok(entry))
+            }
+            Err(e) => {
+                // Nothing after a bad record can be found
+                self.offset = self.end;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, sync::Arc};
+
+    use anyhow::Result;
+
+    use crate::ledger::{
+        Ledger, Transaction, TxStatus, amount::Amount, currency::Currency, store::DiskStore,
+    };
+
+    fn store_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("transactor-store-{}-{}", std::process::id(), name))
+    }
+
+    fn deposit(client: u16, tx: u32) -> Transaction {
+        Transaction::Deposit {
+            client,
+            tx,
+            amount: "10".parse().unwrap(),
+            currency: Currency::NONE,
+        }
+    }
+
+    #[test]
+    fn disputes_find_entries_on_disk() -> Result<()> {
+        let path = store_path("ledger");
+        let mut ledger = Ledger::new().with_transaction_store(DiskStore::create(&path)?)?;
+
+        for tx in 1..=100 {
+            ledger.process_transaction(deposit(1, tx))?;
+        }
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 42,
+            amount: None,
+        })?;
+        assert_eq!(Some("990".parse()?), ledger.get_available_balance(1));
+        assert!(ledger.process_transaction(deposit(1, 42)).is_err());
+
+        let entry = ledger.get_entry(1, 42)?.expect("entry was stored");
+        assert_eq!(TxStatus::Disputed, entry.status());
+
+        ledger.process_transaction(Transaction::Resolve {
+            client: 1,
+            tx: 42,
+            amount: None,
+        })?;
+        assert_eq!(
+            TxStatus::Resolved,
+            ledger.get_entry(1, 42)?.expect("entry was stored").status()
+        );
+
+        drop(ledger);
+        assert!(!path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn partial_disputes_are_written_back() -> Result<()> {
+        let mut ledger =
+            Ledger::new().with_transaction_store(DiskStore::create(store_path("partial"))?)?;
+        ledger.process_transaction(deposit(1, 1))?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            amount: Some("4".parse()?),
+        })?;
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 1,
+            tx: 1,
+            amount: Some("1.5".parse()?),
+        })?;
+
+        let entry = ledger.get_entry(1, 1)?.expect("entry was stored");
+        assert_eq!(TxStatus::Disputed, entry.status());
+        assert_eq!("4".parse::<Amount>()?, entry.disputed());
+        assert_eq!("2.5".parse::<Amount>()?, entry.open());
+
+        Ok(())
+    }
+
+    #[test]
+    fn entries_keep_insertion_order() -> Result<()> {
+        let mut ledger = Ledger::new();
+        for tx in [3, 1, 2] {
+            ledger.process_transaction(deposit(1, tx))?;
+        }
+
+        // Entries already in memory move across to the new store
+        let mut ledger = ledger.with_transaction_store(DiskStore::create(store_path("order"))?)?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        })?;
+
+        let order: Vec<_> = ledger
+            .get_client_history(1, 0, usize::MAX)?
+            .iter()
+            .map(|e| (*e.t.tx(), e.status()))
+            .collect();
+        assert_eq!(
+            vec![
+                (3, TxStatus::Active),
+                (1, TxStatus::Disputed),
+                (2, TxStatus::Active)
+            ],
+            order
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sources_are_shared_between_entries() -> Result<()> {
+        let mut ledger =
+            Ledger::new().with_transaction_store(DiskStore::create(store_path("sources"))?)?;
+        for (tx, source) in [(1, "monday.csv"), (2, "tuesday.csv"), (3, "monday.csv")] {
+            ledger.process_transaction_from(deposit(1, tx), None, Some(source))?;
+        }
+
+        let history = ledger.get_client_history(1, 0, usize::MAX)?;
+        let sources: Vec<_> = history.iter().map(|e| e.source()).collect();
+        assert_eq!(
+            vec![Some("monday.csv"), Some("tuesday.csv"), Some("monday.csv")],
+            sources
+        );
+
+        // Read back as the same name rather than a copy of it
+        let (first, last) = (&history[0].source, &history[2].source);
+        assert!(
+            first
+                .as_ref()
+                .zip(last.as_ref())
+                .is_some_and(|(a, b)| Arc::ptr_eq(a, b))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! Sub module for totals across every client. Operations want to know how much money the
+//! ledger holds in each currency without reading a row for every client.
+
+use std::collections::BTreeMap;
+
+use crate::ledger::{Ledger, amount::Amount, balance, currency::Currency};
+
+/// Totals of every client's balance in one currency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub currency: Currency,
+
+    /// Clients holding a balance in the currency
+    pub clients: usize,
+
+    /// How many of those clients are frozen
+    pub locked: usize,
+
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub fees: Amount,
+}
+
+impl Ledger {
+    /// Totals for each currency any client holds, ordered by currency. Fails if a total
+    /// can't be represented
+    pub fn get_summary(&self) -> Result<Vec<Summary>, balance::Error> {
+        let mut summaries: BTreeMap<Currency, Summary> = BTreeMap::new();
+        for snapshot in self.get_client_snapshots() {
+            let summary = summaries.entry(snapshot.currency).or_insert(Summary {
+                currency: snapshot.currency,
+                clients: 0,
+                locked: 0,
+                available: Amount::ZERO,
+                held: Amount::ZERO,
+                total: Amount::ZERO,
+                fees: Amount::ZERO,
+            });
+            let add = |a: Amount, b: Amount| a.checked_add(b).ok_or(balance::Error::Overflow);
+
+            summary.clients += 1;
+            summary.locked += usize::from(snapshot.locked);
+            summary.available = add(summary.available, snapshot.available)?;
+            summary.held = add(summary.held, snapshot.held)?;
+            summary.total = add(summary.total, snapshot.total)?;
+            summary.fees = add(summary.fees, snapshot.fees)?;
+        }
+        Ok(summaries.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{Ledger, Transaction, amount::Amount, currency::Currency};
+
+    fn amount(s: &str) -> Amount {
+        s.parse().expect("valid amount literal")
+    }
+
+    #[test]
+    fn totals_by_currency() -> Result<()> {
+        let eur: Currency = "EUR".parse()?;
+        let mut ledger = Ledger::new();
+        for (client, tx, currency) in [(1, 1, eur), (2, 2, eur), (1, 3, Currency::NONE)] {
+            ledger.process_transaction(Transaction::Deposit {
+                client,
+                tx,
+                amount: amount("10"),
+                currency,
+            })?;
+        }
+        ledger.process_transaction(Transaction::Dispute {
+            client: 2,
+            tx: 2,
+            amount: Some(amount("4")),
+        })?;
+
+        let summary = ledger.get_summary()?;
+        assert_eq!(2, summary.len());
+        let eur = summary
+            .iter()
+            .find(|s| s.currency == eur)
+            .expect("EUR is held");
+        assert_eq!(2, eur.clients);
+        assert_eq!(amount("16"), eur.available);
+        assert_eq!(amount("4"), eur.held);
+        assert_eq!(amount("20"), eur.total);
+
+        Ok(())
+    }
+}
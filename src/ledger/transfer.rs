@@ -0,0 +1,197 @@
+//! Sub module for transfers between clients. A transfer is applied to both balances or to
+//! neither, so there's no window in which the money has left one client without reaching
+//! the other. It's registered as a single entry of the sender's, and a dispute on it holds
+//! and settles both sides together.
+
+use crate::ledger::{
+    Client, Error, Ledger, Transaction, Tx, amount::Amount, balance, books::Account,
+    currency::Currency,
+};
+
+impl Ledger {
+    /// Move `amount` from the sender's balance to the recipient's
+    pub(crate) fn transfer(
+        &mut self,
+        (from, tx): (Client, Tx),
+        to: Client,
+        amount: Amount,
+        currency: Currency,
+    ) -> Result<(), Error> {
+        if from == to {
+            Err(Error::SelfTransfer(tx))?
+        }
+        // A negative transfer would take money from the recipient instead
+        if !amount.is_positive() {
+            Err(Error::InvalidTransferAmount(tx))?
+        }
+        if self.locked.contains(&to) {
+            Err(Error::FrozenAccountError(to))?
+        }
+
+        self.balance_mut(from, currency).withdraw(amount)?;
+        if let Err(e) = self.balance_mut(to, currency).deposit(amount) {
+            // Give the sender their money back so the transfer leaves no trace
+            self.balance_mut(from, currency)
+                .credit(amount)
+                .expect("The withdrawal was just taken from this total");
+            Err(e)?
+        }
+
+        let (debit, credit) = (Account::Client(from), Account::Client(to));
+        self.post((from, tx), currency, debit, credit, amount)?;
+
+        Ok(())
+    }
+
+    /// Split the hold a dispute places on an entry between the balances it's held on. A
+    /// transfer holds on the recipient while the sender holds the negation, so a charge
+    /// back takes the money back from one and returns it to the other. Every other entry
+    /// holds on its own client
+    pub(crate) fn split_hold(
+        &self,
+        entry: &Transaction,
+        hold: Amount,
+    ) -> Result<Vec<(Client, Amount)>, Error> {
+        let Some(to) = entry.recipient() else {
+            return Ok(vec![(*entry.client(), hold)]);
+        };
+
+        let negated = hold.checked_neg().ok_or(balance::Error::Overflow)?;
+
+        Ok(vec![(to, hold), (*entry.client(), negated)])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{
+        AdminAction, Error, Ledger, Transaction, TxStatus, amount::Amount, balance,
+        currency::Currency,
+    };
+
+    fn amount(s: &str) -> Amount {
+        s.parse().expect("valid amount literal")
+    }
+
+    fn deposit(client: u16, tx: u32, value: &str) -> Transaction {
+        Transaction::Deposit {
+            client,
+            tx,
+            amount: amount(value),
+            currency: Currency::NONE,
+        }
+    }
+
+    fn transfer(from: u16, to: u16, tx: u32, value: &str) -> Transaction {
+        Transaction::Transfer {
+            from,
+            to,
+            tx,
+            amount: amount(value),
+            currency: Currency::NONE,
+        }
+    }
+
+    #[test]
+    fn transfers_apply_to_both_sides_or_neither() -> Result<()> {
+        let mut ledger = Ledger::new().with_double_entry()?;
+        ledger.process_transaction(deposit(1, 1, "100"))?;
+        ledger.process_transaction(transfer(1, 2, 2, "40"))?;
+
+        assert_eq!(amount("60"), ledger.get_client_snapshot(1)[0].total);
+        assert_eq!(amount("40"), ledger.get_client_snapshot(2)[0].total);
+        assert!(ledger.unbalanced_accounts().is_empty());
+
+        // Neither side changes when either side refuses
+        assert!(matches!(
+            ledger.process_transaction(transfer(1, 2, 3, "61")),
+            Err(Error::BalanceError(balance::Error::InsufficientFunds))
+        ));
+        ledger.process_transaction(Transaction::Admin {
+            client: 2,
+            tx: 4,
+            action: AdminAction::Freeze,
+            operator: 1,
+            reason: "review".to_string(),
+        })?;
+        assert!(matches!(
+            ledger.process_transaction(transfer(1, 2, 5, "10")),
+            Err(Error::FrozenAccountError(2))
+        ));
+        assert!(matches!(
+            ledger.process_transaction(transfer(1, 1, 6, "10")),
+            Err(Error::SelfTransfer(6))
+        ));
+
+        assert_eq!(amount("60"), ledger.get_client_snapshot(1)[0].total);
+        assert_eq!(amount("40"), ledger.get_client_snapshot(2)[0].total);
+
+        Ok(())
+    }
+
+    #[test]
+    fn transfers_must_be_positive() -> Result<()> {
+        let mut ledger = Ledger::new().with_double_entry()?;
+        ledger.process_transaction(deposit(1, 1, "100"))?;
+        ledger.process_transaction(deposit(2, 2, "100"))?;
+
+        assert!(matches!(
+            ledger.process_transaction(transfer(1, 2, 3, "-50")),
+            Err(Error::InvalidTransferAmount(3))
+        ));
+        assert!(matches!(
+            ledger.process_transaction(transfer(1, 2, 4, "0")),
+            Err(Error::InvalidTransferAmount(4))
+        ));
+
+        assert_eq!(amount("100"), ledger.get_client_snapshot(1)[0].total);
+        assert_eq!(amount("100"), ledger.get_client_snapshot(2)[0].total);
+        assert!(ledger.get_entry(1, 3)?.is_none());
+        assert!(ledger.get_entry(1, 4)?.is_none());
+        assert_eq!(2, ledger.books().expect("books are kept").postings().len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn charge_back_reverses_both_sides() -> Result<()> {
+        let mut ledger = Ledger::new().with_double_entry()?;
+        ledger.process_transaction(deposit(1, 1, "100"))?;
+        ledger.process_transaction(transfer(1, 2, 2, "40"))?;
+        ledger.process_transaction(Transaction::Dispute {
+            client: 1,
+            tx: 2,
+            amount: None,
+        })?;
+
+        // The recipient can't spend what's in dispute, and the sender doesn't get it back yet
+        let recipient = ledger.get_client_snapshot(2)[0];
+        assert_eq!(amount("40"), recipient.held);
+        assert_eq!(Amount::ZERO, recipient.available);
+        assert_eq!(amount("60"), ledger.get_client_snapshot(1)[0].available);
+
+        ledger.process_transaction(Transaction::ChargeBack {
+            client: 1,
+            tx: 2,
+            amount: None,
+        })?;
+        let sender = ledger.get_client_snapshot(1)[0];
+        let recipient = ledger.get_client_snapshot(2)[0];
+        assert_eq!(amount("100"), sender.total);
+        assert!(sender.locked);
+        assert_eq!(Amount::ZERO, recipient.total);
+        assert_eq!(Amount::ZERO, recipient.held);
+        assert_eq!(
+            TxStatus::ChargedBack,
+            ledger
+                .get_entry(1, 2)?
+                .expect("entry was registered")
+                .status()
+        );
+        assert!(ledger.unbalanced_accounts().is_empty());
+
+        Ok(())
+    }
+}
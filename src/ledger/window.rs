@@ -0,0 +1,207 @@
+//! Sub module for limiting disputes in time. An entry may only be disputed for so long after
+//! it happened, and a dispute which is left open past its deadline is settled automatically
+//! once the ledger sees a transaction from after the deadline.
+
+use crate::ledger::{Error, Ledger, Timestamp};
+
+/// Seconds in a day, for windows configured in days
+pub const DAY: u64 = 24 * 60 * 60;
+
+/// How an open dispute is settled once its deadline passes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeadlineAction {
+    /// Release the hold back to the client
+    #[default]
+    Resolve,
+    /// Charge the dispute back, as though a charge back had arrived
+    ChargeBack,
+}
+
+/// Limits on when disputes may be raised and how long they may stay open. Both limits
+/// are off by default. Transactions without a timestamp are taken to happen at the
+/// latest time the ledger has seen, and entries from before any timestamp was seen may
+/// always be disputed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DisputeWindow {
+    /// Seconds after an entry within which it may be disputed
+    pub dispute_within: Option<u64>,
+
+    /// Seconds after a dispute is raised within which it must be resolved or charged back
+    pub resolve_within: Option<u64>,
+
+    /// What happens to a dispute which is still open at its deadline
+    pub on_deadline: DeadlineAction,
+}
+
+impl DisputeWindow {
+    /// Whether an entry from `entry_at` may still be disputed at `now`
+    pub fn accepts_dispute(&self, entry_at: Option<Timestamp>, now: Option<Timestamp>) -> bool {
+        match (self.dispute_within, entry_at, now) {
+            (Some(within), Some(entry_at), Some(now)) => now.saturating_sub(entry_at) <= within,
+            _ => true,
+        }
+    }
+
+    /// When a dispute raised at `now` must be settled by
+    pub fn deadline(&self, now: Option<Timestamp>) -> Option<Timestamp> {
+        Some(now?.saturating_add(self.resolve_within?))
+    }
+}
+
+impl Ledger {
+    /// Replace the default [`DisputeWindow`], under which disputes are never limited in time
+    pub fn with_dispute_window(mut self, window: DisputeWindow) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// The latest timestamp the ledger has seen
+    pub fn clock(&self) -> Option<Timestamp> {
+        self.clock
+    }
+
+    /// Move the ledger's clock forward to `at` and settle every open dispute whose
+    /// deadline is before then. The clock never moves backwards.
+    ///
+    /// If a dispute can't be settled the error is returned and the clock stays where it
+    /// was, so the dispute is tried again the next time the clock moves. Disputes settled
+    /// before it stay settled.
+    pub fn advance_clock(&mut self, at: Timestamp) -> Result<(), Error> {
+        if self.clock.is_some_and(|clock| clock >= at) {
+            return Ok(());
+        }
+        let previous = self.clock.replace(at);
+
+        while let Some(&(deadline, client, tx)) = self.deadlines.first() {
+            if deadline >= at {
+                break;
+            }
+
+            // The deadline applies even to a frozen account, so this skips the checks made
+            // on incoming transactions
+            let on_deadline = self.window.on_deadline;
+            let settled = self.atomically(client, |ledger| match on_deadline {
+                DeadlineAction::Resolve => ledger.resolve_dispute(&(client, tx), None),
+                DeadlineAction::ChargeBack => ledger.charge_back_dispute(&(client, tx), None),
+            });
+            if let Err(e) = settled {
+                self.clock = previous;
+                return Err(e);
+            }
+            self.deadlines.remove(&(deadline, client, tx));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::ledger::{
+        Error, Ledger, Transaction,
+        amount::Amount,
+        currency::Currency,
+        window::{DAY, DeadlineAction, DisputeWindow},
+    };
+
+    fn deposit(tx: u32) -> Transaction {
+        Transaction::Deposit {
+            client: 0,
+            tx,
+            amount: "100".parse().unwrap(),
+            currency: Currency::NONE,
+        }
+    }
+
+    fn window(on_deadline: DeadlineAction) -> DisputeWindow {
+        DisputeWindow {
+            dispute_within: Some(120 * DAY),
+            resolve_within: Some(30 * DAY),
+            on_deadline,
+        }
+    }
+
+    #[test]
+    fn late_disputes_are_rejected() -> Result<()> {
+        let mut ledger = Ledger::new().with_dispute_window(window(DeadlineAction::Resolve));
+        ledger.process_transaction_at(deposit(1), Some(0))?;
+        ledger.process_transaction_at(deposit(2), Some(100 * DAY))?;
+
+        assert!(matches!(
+            ledger.process_transaction_at(
+                Transaction::Dispute {
+                    client: 0,
+                    tx: 1,
+                    amount: None,
+                },
+                Some(121 * DAY)
+            ),
+            Err(Error::DisputeWindowClosed(1))
+        ));
+
+        // Without its own timestamp the dispute happens at the latest time seen
+        ledger.process_transaction(Transaction::Dispute {
+            client: 0,
+            tx: 2,
+            amount: None,
+        })?;
+        assert_eq!(Some("100".parse()?), ledger.get_available_balance(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn expired_disputes_are_settled() -> Result<()> {
+        let mut ledger = Ledger::new().with_dispute_window(window(DeadlineAction::Resolve));
+        ledger.process_transaction_at(deposit(1), Some(0))?;
+        ledger.process_transaction_at(
+            Transaction::Dispute {
+                client: 0,
+                tx: 1,
+                amount: None,
+            },
+            Some(DAY),
+        )?;
+        assert_eq!(Some("0".parse()?), ledger.get_available_balance(0));
+
+        // Still open exactly at the deadline
+        ledger.process_transaction_at(deposit(2), Some(31 * DAY))?;
+        assert_eq!(Some("100".parse()?), ledger.get_available_balance(0));
+
+        ledger.process_transaction_at(deposit(3), Some(31 * DAY + 1))?;
+        assert_eq!(Some("300".parse()?), ledger.get_available_balance(0));
+        assert!(matches!(
+            ledger.process_transaction(Transaction::Resolve {
+                client: 0,
+                tx: 1,
+                amount: None,
+            }),
+            Err(Error::UnexpectedTxStatus(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn expired_disputes_charge_back() -> Result<()> {
+        let mut ledger = Ledger::new().with_dispute_window(window(DeadlineAction::ChargeBack));
+        ledger.process_transaction_at(deposit(1), Some(0))?;
+        ledger.process_transaction_at(
+            Transaction::Dispute {
+                client: 0,
+                tx: 1,
+                amount: None,
+            },
+            Some(0),
+        )?;
+
+        ledger.advance_clock(60 * DAY)?;
+        let snapshot = ledger.get_client_snapshots()[0];
+        assert!(snapshot.locked);
+        assert_eq!("0".parse::<Amount>()?, snapshot.total);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,23 @@
+//! Transaction processing engine. Transactions are read from CSV, applied to a
+//! [`Ledger`] which tracks the balance of every client, and the final balances
+//! are written back out as CSV, JSON or a table.
+//!
+//! The binary in this crate is a thin consumer of this library so the same engine
+//! can be embedded within other services.
+
+pub mod csv;
+pub mod journal;
+pub mod ledger;
+pub mod reject;
+pub mod report;
+pub mod server;
+
+#[cfg(test)]
+#[allow(clippy::same_item_push, clippy::unused_io_amount)]
+mod string;
+
+pub use crate::ledger::{
+    Client, Ledger, Transaction, Tx, TxStatus,
+    amount::{Amount, ParseAmountError},
+    balance::{Balance, BalanceSnapshot},
+};
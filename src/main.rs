@@ -1,71 +1,534 @@
-use std::{fs::OpenOptions, io::BufReader};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 
-use ::csv::ReaderBuilder;
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser, error::ErrorKind};
+use transactor::{
+    Ledger,
+    csv::{
+        Merge, MergeBy, Row, TransactionReader, read_credit_limits, read_fee_schedule,
+        write_balances_to_file, write_history_to_file, write_summary_to_file,
+        write_trial_balance_to_file,
+    },
+    journal::Journal,
+    ledger::{
+        self,
+        policy::{DepositOnlyPolicy, StandardPolicy},
+        shard::ShardedLedger,
+        store::{DiskStore, MemoryStore, TransactionStore},
+    },
+    reject::{Rejection, write_rejections_to_file},
+    report::Format,
+    server::{DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CONNECTIONS, Server},
+};
 
-use crate::{
-    csv::{CsvTransaction, write_balances_to_file},
-    ledger::Ledger,
+use crate::cli::{
+    Cli, Command, HistoryArgs, LayoutArgs, LedgerArgs, PolicyArg, ProcessArgs, SummaryArgs,
+    ValidateArgs,
 };
 
-mod csv;
-mod ledger;
+mod cli;
 
-#[cfg(test)]
-mod string;
+/// Exit status when some rows were rejected. `EX_DATAERR` from sysexits.h
+const EXIT_REJECTED: u8 = 65;
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
+/// Exit status when an input couldn't be read or an output couldn't be written.
+/// `EX_IOERR` from sysexits.h. Mistakes on the command line exit with 2
+const EXIT_IO: u8 = 74;
 
-    // The filename we're looking for is the first argument which isn't '--'
-    let filename = {
-        let mut i = 1; // Skip over the name of the binary
-        while args[i] == "--" {
-            i += 1;
-        }
-        args[i].clone()
+/// Exit status when the options can't be used with the input, the same as clap gives for
+/// options which can't be used together
+const EXIT_USAGE: u8 = 2;
+
+/// Exit status when the trial balance disagrees with the balances. `EX_SOFTWARE` from
+/// sysexits.h, as books which don't agree mean the ledger went wrong somewhere
+const EXIT_UNBALANCED: u8 = 70;
+
+/// The books disagreed with the balances on this many accounts. Everything else the run
+/// was asked to write is still written
+#[derive(Debug, thiserror::Error)]
+#[error("Accounts on which the trial balance disagrees with the balances: {0}")]
+struct Unbalanced(usize);
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    // Without a subcommand the input is processed
+    let result = match cli.command.unwrap_or(Command::Process(cli.process)) {
+        Command::Process(args) => process(args),
+        Command::Validate(args) => validate(args),
+        Command::Summary(args) => summary(args),
+        Command::History(args) => history(args),
     };
 
-    let f = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .create(false)
-        .truncate(false)
-        .open(filename)
-        .expect("File should be available");
+    match result {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(rejected) => {
+            eprintln!("Rows rejected: {}", rejected);
+            ExitCode::from(EXIT_REJECTED)
+        }
+        // A row which couldn't be read in strict mode
+        Err(e) if e.is::<Rejection>() => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(EXIT_REJECTED)
+        }
+        Err(e) if matches!(e.downcast_ref(), Some(ledger::Error::ShardedTransfer(_))) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(EXIT_USAGE)
+        }
+        Err(e) if e.is::<Unbalanced>() => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(EXIT_UNBALANCED)
+        }
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            ExitCode::from(EXIT_IO)
+        }
+    }
+}
+
+/// Apply the input and write the balance of every client. Returns how many rows were rejected
+fn process(args: ProcessArgs) -> Result<usize> {
+    let (mut ledger, mut journal) = open_ledger(&args.ledger)?;
+
+    // Serve transactions over TCP instead of reading them from a file
+    if let Some(addr) = args.serve {
+        let server = Server::bind(&addr, ledger, journal)
+            .with_context(|| format!("Couldn't listen on {}", addr))?
+            .allow_admin(args.ledger.allow_admin)
+            .max_connections(args.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS))
+            .idle_timeout(Some(
+                args.idle_timeout
+                    .map_or(DEFAULT_IDLE_TIMEOUT, Duration::from_secs),
+            ));
+        eprintln!("Listening on {}", server.local_addr()?);
+        server
+            .run()
+            .context("Server stopped accepting connections")?;
+        return Ok(0);
+    }
+
+    if args.input.is_empty() {
+        missing_input("an input file is required unless serving")
+    }
+    let rejections;
+    (ledger, rejections) = apply_inputs(&args.input, ledger, journal.as_mut(), &args.ledger)?;
+
+    write_balances_to_file(
+        &ledger.get_client_snapshots_by(args.order(), args.filter()),
+        args.report.format(),
+        output(args.report.output.as_deref())?,
+    )
+    .context("Couldn't write the balances")?;
+
+    finish(
+        ledger,
+        journal,
+        &args.ledger,
+        args.report.format(),
+        &rejections,
+    )
+}
+
+/// Report every row which can't be read, without applying any of them
+fn validate(args: ValidateArgs) -> Result<usize> {
+    let rejections: Vec<Rejection> =
+        read_inputs(&args.input, args.allow_admin, &args.layout, None)?
+            .filter_map(Result::err)
+            .collect();
 
-    // Track all transactions in this file.
-    let mut ledger = Ledger::new();
+    write_rejections_to_file(
+        &rejections,
+        args.report.format(),
+        output(args.report.output.as_deref())?,
+    )
+    .context("Couldn't write the report")?;
 
-    // Need to read the file in
-    let reader = BufReader::new(&f);
+    Ok(rejections.len())
+}
+
+/// Apply the input and write the totals of each currency
+fn summary(args: SummaryArgs) -> Result<usize> {
+    let (ledger, mut journal) = open_ledger(&args.ledger)?;
+    let (ledger, rejections) = apply_inputs(&args.input, ledger, journal.as_mut(), &args.ledger)?;
 
-    // Parse the contents with our CSV library
-    let mut csv_reader = ReaderBuilder::new().has_headers(true).from_reader(reader);
+    let summary = ledger
+        .get_summary()
+        .context("Totals are too large to represent")?;
+    write_summary_to_file(
+        &summary,
+        args.report.format(),
+        output(args.report.output.as_deref())?,
+    )
+    .context("Couldn't write the summary")?;
 
-    let headers = csv_reader.headers().expect("headers to be present").clone();
+    finish(
+        ledger,
+        journal,
+        &args.ledger,
+        args.report.format(),
+        &rejections,
+    )
+}
 
-    for record in csv_reader.records() {
-        match record {
-            Ok(r) => {
-                let tx: CsvTransaction = match r.deserialize(Some(&headers)) {
-                    Ok(tx) => tx,
-                    Err(_) => continue,
-                };
+/// Write a client's entries from the input, the saved state, or both. The journal is only
+/// read, so the input is never added to it
+fn history(args: HistoryArgs) -> Result<usize> {
+    // A saved state would overlap the journal, which is only emptied once it's written to
+    if args.ledger.journal.is_some() && args.ledger.save_state.is_some() {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--save-state can't be used with --journal when writing history",
+            )
+            .exit()
+    }
+    let mut ledger = read_ledger(&args.ledger)?;
 
-                let tx = match tx.try_into() {
-                    Ok(tx) => tx,
-                    Err(_) => continue,
-                };
+    let mut rejections = Vec::new();
+    if !args.input.is_empty() {
+        (ledger, rejections) = apply_inputs(&args.input, ledger, None, &args.ledger)?
+    } else if args.ledger.load_state.is_none() && args.ledger.journal.is_none() {
+        missing_input("an input file is required without --load-state or --journal")
+    }
 
-                let _ = ledger.process_transaction(tx);
+    let entries = match args.tx {
+        Some(tx) => {
+            let entry = ledger.get_entry(args.client, tx)?;
+            if entry.is_none() {
+                eprintln!("No transaction {} for client {}", tx, args.client);
             }
-            Err(_) => (), // Do nothing on bad entries in the CSV
+            entry.into_iter().collect()
+        }
+        None => ledger.get_client_history(args.client, args.offset, args.limit)?,
+    };
+    write_history_to_file(
+        &entries,
+        args.report.format(),
+        output(args.report.output.as_deref())?,
+    )
+    .context("Couldn't write the history")?;
+
+    finish(
+        ledger,
+        None,
+        &args.ledger,
+        args.report.format(),
+        &rejections,
+    )
+}
+
+/// Exit with a usage error for a missing input
+fn missing_input(message: &str) -> ! {
+    Cli::command()
+        .error(ErrorKind::MissingRequiredArgument, message)
+        .exit()
+}
+
+/// Open an input for reading
+fn open(path: &Path) -> Result<File> {
+    File::open(path).with_context(|| format!("Couldn't open {}", path.display()))
+}
+
+/// Where a report is written. Standard output unless a path is given
+fn output(path: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(
+            File::create(path).with_context(|| format!("Couldn't create {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stdout()),
+    })
+}
+
+/// Continue on from a previous run when asked to. Saved entries go straight into `store`
+/// so a large state never has to fit in memory
+fn restore(args: &LedgerArgs, store: impl TransactionStore + 'static) -> Result<Ledger> {
+    Ok(match &args.load_state {
+        Some(path) => Ledger::load_from_file(path, store)
+            .with_context(|| format!("Couldn't load the state in {}", path.display()))?,
+        None => Ledger::new().with_transaction_store(store)?,
+    })
+}
+
+/// Build the ledger the options describe, along with the journal if one is kept
+fn open_ledger(args: &LedgerArgs) -> Result<(Ledger, Option<Journal>)> {
+    let mut ledger = build_ledger(args)?;
+
+    // Anything accepted since the state was last saved is recovered from the journal
+    let journal = match &args.journal {
+        Some(path) => {
+            let (journal, replay) = Journal::open(path, args.fsync, &mut ledger)
+                .with_context(|| format!("Couldn't replay the journal {}", path.display()))?;
+            if replay.truncated > 0 {
+                eprintln!(
+                    "Truncated a torn record of {} bytes from the journal",
+                    replay.truncated
+                );
+            }
+            Some(journal)
+        }
+        None => None,
+    };
+
+    Ok((ledger, journal))
+}
+
+/// Build the ledger the options describe to read from rather than carry on. The journal
+/// is replayed if one is given, but never written to
+fn read_ledger(args: &LedgerArgs) -> Result<Ledger> {
+    let mut ledger = build_ledger(args)?;
+
+    if let Some(path) = &args.journal {
+        let replay = Journal::replay(path, &mut ledger)
+            .with_context(|| format!("Couldn't replay the journal {}", path.display()))?;
+        if replay.truncated > 0 {
+            eprintln!(
+                "Skipped a torn record of {} bytes at the end of the journal",
+                replay.truncated
+            );
+        }
+    }
+
+    Ok(ledger)
+}
+
+/// Build the ledger the options describe from the saved state, if any
+fn build_ledger(args: &LedgerArgs) -> Result<Ledger> {
+    // Entries are kept on disk rather than in memory when asked to
+    let ledger = match &args.store {
+        Some(path) => restore(
+            args,
+            DiskStore::create(path)
+                .with_context(|| format!("Couldn't create the store {}", path.display()))?,
+        )?,
+        None => restore(args, MemoryStore::default())?,
+    };
+
+    // Books restored with the state are always kept
+    let ledger = if args.double_entry || args.trial_balance.is_some() {
+        ledger.with_double_entry()?
+    } else {
+        ledger
+    };
+
+    // The policy, window, limits and fees aren't part of the saved state, they're chosen fresh on every run
+    let ledger = ledger
+        .with_dispute_window(args.window())
+        .with_representment_unlock(args.unlock_on_represent);
+    let ledger = match &args.fees {
+        Some(path) => {
+            let fees = read_fee_schedule(open(path)?)
+                .with_context(|| format!("Couldn't read the fee schedule {}", path.display()))?;
+            ledger.with_fee_schedule(fees)
         }
+        None => ledger,
+    };
+    let ledger = match &args.credit_limits {
+        Some(path) => {
+            let limits = read_credit_limits(open(path)?)
+                .with_context(|| format!("Couldn't read the credit limits {}", path.display()))?;
+            ledger.with_credit_limits(limits)
+        }
+        None => ledger,
+    };
+    Ok(match args.dispute_policy {
+        PolicyArg::Standard => ledger.with_dispute_policy(StandardPolicy),
+        PolicyArg::DepositsOnly => ledger.with_dispute_policy(DepositOnlyPolicy),
+    })
+}
+
+/// Write everything the options ask for once the input has been applied. Returns how
+/// many rows were rejected, or [`Unbalanced`] once everything is written if the books
+/// disagree with the balances
+fn finish(
+    ledger: Ledger,
+    mut journal: Option<Journal>,
+    args: &LedgerArgs,
+    format: Format,
+    rejections: &[Rejection],
+) -> Result<usize> {
+    let mut unbalanced = 0;
+    if let Some(path) = &args.trial_balance {
+        let books = ledger
+            .books()
+            .expect("Books are kept when a trial balance is asked for");
+        let f = File::create(path)
+            .with_context(|| format!("Couldn't create the trial balance {}", path.display()))?;
+        write_trial_balance_to_file(&books.trial_balance(), format, f)
+            .context("Couldn't write the trial balance")?;
+        for (currency, account) in ledger.unbalanced_accounts() {
+            eprintln!(
+                "Trial balance disagrees with the balances on {} in {}",
+                account, currency
+            );
+            unbalanced += 1;
+        }
+    }
+
+    if let Some(path) = &args.save_state {
+        ledger
+            .save_to_file(path)
+            .with_context(|| format!("Couldn't save the state to {}", path.display()))?;
+
+        // Everything in the journal is now covered by the saved state
+        if let Some(journal) = journal.as_mut() {
+            journal.checkpoint().context("Couldn't empty the journal")?;
+        }
+    } else if let Some(journal) = journal.as_mut() {
+        journal.sync().context("Couldn't sync the journal")?;
+    }
+
+    if let Some(path) = &args.rejects {
+        let f = File::create(path)
+            .with_context(|| format!("Couldn't create the rejects report {}", path.display()))?;
+        write_rejections_to_file(rejections, format, f)
+            .context("Couldn't write the rejects report")?;
+    }
+
+    if unbalanced > 0 {
+        Err(Unbalanced(unbalanced))?
+    }
+
+    Ok(rejections.len())
+}
+
+/// Open an input and read its header row. `-` reads standard input
+fn read_file(
+    path: &Path,
+    allow_admin: bool,
+    layout: &LayoutArgs,
+) -> Result<TransactionReader<Box<dyn Read>>> {
+    let (input, source): (Box<dyn Read>, _) = if path == Path::new("-") {
+        (Box::new(BufReader::new(io::stdin())), "stdin".to_string())
+    } else {
+        (
+            Box::new(BufReader::new(open(path)?)),
+            path.display().to_string(),
+        )
+    };
+    let mut transactions = TransactionReader::with_delimiter(input, layout.delimiter)
+        .with_context(|| format!("Couldn't read the header row of {}", source))?;
+    if let Some(columns) = &layout.column_map {
+        transactions = transactions.map_columns(columns);
     }
+    Ok(transactions.allow_admin(allow_admin).source(&source))
+}
 
-    let snapshots = ledger.get_client_snapshots();
+/// Every row of the inputs, one input after another or merged on a column
+fn read_inputs(
+    paths: &[PathBuf],
+    allow_admin: bool,
+    layout: &LayoutArgs,
+    merge_by: Option<MergeBy>,
+) -> Result<Box<dyn Iterator<Item = Result<Row, Rejection>>>> {
+    let inputs = paths
+        .iter()
+        .map(|path| read_file(path, allow_admin, layout))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(match merge_by {
+        Some(by) => Box::new(Merge::new(inputs, by)),
+        None => Box::new(inputs.into_iter().flatten()),
+    })
+}
+
+/// Apply every row of the inputs to the ledger, journaling each accepted transaction.
+/// Returns the ledger along with every row which wasn't applied, in input order
+fn apply_inputs(
+    paths: &[PathBuf],
+    mut ledger: Ledger,
+    mut journal: Option<&mut Journal>,
+    args: &LedgerArgs,
+) -> Result<(Ledger, Vec<Rejection>)> {
+    let transactions = read_inputs(paths, args.allow_admin, &args.layout, args.merge_by())?;
 
-    let writer = std::io::stdout();
+    // Every row which isn't applied is kept for the rejects report
+    let mut rejections = Vec::new();
+    let mut skipped = 0;
+    let mut skip = |rejection: Rejection| -> Result<Rejection> {
+        if args.strict {
+            return Err(rejection.into());
+        }
+        eprintln!("Skipped {}", rejection);
+        skipped += 1;
+        Ok(rejection)
+    };
+
+    if args.threads > 1 {
+        // Acceptance is only known once the workers finish, so nothing could be journaled in
+        // time. The options conflict, so there's never a journal here
+        // Rows are tagged with their position across every input since lines repeat between them
+        let mut sharded = ShardedLedger::new(ledger, usize::from(args.threads))?;
+        let mut positioned = Vec::new();
+        for (idx, row) in transactions.enumerate() {
+            match row {
+                Ok(row) => {
+                    let (t, at) = (row.transaction.clone(), row.timestamp);
+                    let source = row.source.clone();
+                    if let Err(e) = sharded.submit_from((idx, row), t, at, source) {
+                        // Let the workers wind down so their stores are cleaned up
+                        sharded.finish()?;
+                        return Err(e).context("Transfers can only be processed without --threads");
+                    }
+                }
+                Err(rejection) => positioned.push((idx, skip(rejection)?)),
+            }
+        }
+
+        let (merged, rejected) = sharded.finish()?;
+        ledger = merged;
+        positioned.extend(
+            rejected
+                .into_iter()
+                .map(|((idx, row), e)| (idx, Rejection::from_ledger(row, &e))),
+        );
+
+        // Workers report their rejections independently, put them back in input order
+        positioned.sort_by_key(|(idx, _)| *idx);
+        rejections.extend(positioned.into_iter().map(|(_, rejection)| rejection));
+    } else {
+        for row in transactions {
+            match row {
+                Ok(row) => {
+                    let (t, at, source) = (
+                        row.transaction.clone(),
+                        row.timestamp,
+                        row.source.as_deref(),
+                    );
+                    let clock = ledger.clock();
+                    match ledger.process_transaction_from(t, at, source) {
+                        Ok(()) => {
+                            if let Some(journal) = journal.as_deref_mut() {
+                                journal
+                                    .append(&row.transaction, row.timestamp, source)
+                                    .context("Couldn't append to the journal")?;
+                            }
+                        }
+                        Err(e) => {
+                            // A refused transaction still moves the clock on, which may
+                            // have settled expired disputes
+                            if let (Some(journal), Some(at)) =
+                                (journal.as_deref_mut(), row.timestamp)
+                                && clock.is_none_or(|clock| clock < at)
+                            {
+                                journal.tick(at).context("Couldn't append to the journal")?;
+                            }
+                            rejections.push(Rejection::from_ledger(row, &e))
+                        }
+                    }
+                }
+                Err(rejection) => rejections.push(skip(rejection)?),
+            }
+        }
+    }
+
+    if skipped > 0 {
+        eprintln!("Rows skipped: {}", skipped);
+    }
 
-    let _ = write_balances_to_file(&snapshots, writer);
+    Ok((ledger, rejections))
 }
@@ -0,0 +1,248 @@
+//! Reporting of the transactions which could not be applied. Every rejected row is
+//! recorded with where it came from and a stable reason code so that nothing is lost silently.
+
+use std::{
+    fmt::{Debug, Display},
+    io::Write,
+};
+
+use serde::Serialize;
+
+use crate::{
+    csv::{self, Row},
+    ledger::{self, Tx, balance},
+    report::{self, Format, write_records},
+};
+
+/// Machine readable reason a transaction was rejected. The variant names are the
+/// stable codes written into the rejects report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Reason {
+    /// The input couldn't be read at all
+    Unreadable,
+    /// The record couldn't be deserialized into a transaction
+    Malformed,
+    MissingAmount,
+    MissingOperator,
+    MissingReason,
+    MissingRecipient,
+    AdminNotAllowed,
+    UnknownTransactionType,
+    DuplicateTransaction,
+    MissingTransaction,
+    UnexpectedTxStatus,
+    FrozenAccount,
+    AccountNotFrozen,
+    NotDisputable,
+    DisputeWindowClosed,
+    InvalidDisputeAmount,
+    DisputeAmountExceeded,
+    SelfTransfer,
+    /// A transfer of an amount which isn't positive
+    InvalidTransferAmount,
+    /// A deposit, withdrawal or manual correction of an amount which isn't positive
+    NonPositiveAmount,
+    /// A transfer given to a ledger split between worker threads
+    ShardedTransfer,
+    /// A client charged more fees than there are ids kept for them
+    FeeTxExhausted,
+    InsufficientFunds,
+    CreditLimitExceeded,
+    MultiHold,
+    NoHold,
+    Overflow,
+    /// The transaction store couldn't be read or written
+    StoreUnavailable,
+}
+
+impl Display for Reason {
+    /// Writes the stable reason code
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+impl From<&csv::Error> for Reason {
+    fn from(value: &csv::Error) -> Self {
+        match value {
+            csv::Error::MissingAmount(_) => Reason::MissingAmount,
+            csv::Error::UnknownTransactionType(_) => Reason::UnknownTransactionType,
+            csv::Error::EmptyRecord => Reason::Malformed,
+            csv::Error::MissingOperator(_) => Reason::MissingOperator,
+            csv::Error::MissingReason(_) => Reason::MissingReason,
+            csv::Error::MissingRecipient(_) => Reason::MissingRecipient,
+            csv::Error::AdminNotAllowed(_) => Reason::AdminNotAllowed,
+            csv::Error::NegativeCreditLimit(_)
+            | csv::Error::NegativeFee(_)
+            | csv::Error::InvalidColumnMap(_) => Reason::Malformed,
+            csv::Error::IOError(_) => Reason::Unreadable,
+            csv::Error::CSVError(e) if e.is_io_error() => Reason::Unreadable,
+            csv::Error::CSVError(_) => Reason::Malformed,
+        }
+    }
+}
+
+impl From<&ledger::Error> for Reason {
+    fn from(value: &ledger::Error) -> Self {
+        match value {
+            ledger::Error::DuplicateTransaction(_) => Reason::DuplicateTransaction,
+            ledger::Error::MissingTransaction(_) => Reason::MissingTransaction,
+            ledger::Error::UnexpectedTxStatus(_) => Reason::UnexpectedTxStatus,
+            ledger::Error::FrozenAccountError(_) => Reason::FrozenAccount,
+            ledger::Error::AccountNotFrozen(_) => Reason::AccountNotFrozen,
+            ledger::Error::NotDisputable(_) => Reason::NotDisputable,
+            ledger::Error::DisputeWindowClosed(_) => Reason::DisputeWindowClosed,
+            ledger::Error::InvalidDisputeAmount(_) => Reason::InvalidDisputeAmount,
+            ledger::Error::DisputeAmountExceeded(_) => Reason::DisputeAmountExceeded,
+            ledger::Error::SelfTransfer(_) => Reason::SelfTransfer,
+            ledger::Error::InvalidTransferAmount(_) => Reason::InvalidTransferAmount,
+            ledger::Error::ShardedTransfer(_) => Reason::ShardedTransfer,
+            ledger::Error::FeeTxExhausted(_) => Reason::FeeTxExhausted,
+            ledger::Error::BalanceError(e) => match e {
+                balance::Error::NonPositiveAmount => Reason::NonPositiveAmount,
+                balance::Error::InsufficientFunds => Reason::InsufficientFunds,
+                balance::Error::CreditLimitExceeded => Reason::CreditLimitExceeded,
+                balance::Error::AccountLocked => Reason::FrozenAccount,
+                balance::Error::MultiHoldError(_) => Reason::MultiHold,
+                balance::Error::NoHoldError(_) => Reason::NoHold,
+                balance::Error::Overflow => Reason::Overflow,
+            },
+            ledger::Error::StoreError(_) => Reason::StoreUnavailable,
+        }
+    }
+}
+
+/// A single row of input which was not applied to the ledger
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    /// Line of the input the record started on
+    pub line: u64,
+
+    /// The record as it appeared in the input
+    pub record: String,
+
+    /// Transaction id of the record if one could be read
+    pub tx: Option<Tx>,
+
+    pub reason: Reason,
+
+    /// Human readable description of the failure
+    pub detail: String,
+
+    /// The input the record was read from, if it was named
+    pub source: Option<String>,
+
+    /// Column of the record at fault, when it's known
+    pub column: Option<String>,
+}
+
+impl Rejection {
+    pub fn new<E>(line: u64, record: String, tx: Option<Tx>, error: &E) -> Self
+    where
+        E: std::error::Error,
+        for<'a> Reason: From<&'a E>,
+    {
+        Rejection {
+            line,
+            record,
+            tx,
+            reason: Reason::from(error),
+            detail: error.to_string(),
+            source: None,
+            column: None,
+        }
+    }
+
+    /// Record the input the rejected record was read from
+    pub fn from_source(mut self, source: Option<&str>) -> Self {
+        self.source = source.map(str::to_string);
+        self
+    }
+
+    /// Record the column of the record at fault
+    pub fn at_column(mut self, column: Option<String>) -> Self {
+        self.column = column;
+        self
+    }
+
+    /// A row which was read successfully but refused by the ledger
+    pub fn from_ledger(row: Row, error: &ledger::Error) -> Self {
+        let tx = Some(*row.transaction.tx());
+        Self::new(row.line, row.record, tx, error).from_source(row.source.as_deref())
+    }
+}
+
+impl Display for Rejection {
+    /// Writes where the record was found followed by what was wrong with it
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(source) = &self.source {
+            write!(f, "{}, ", source)?;
+        }
+        write!(f, "line {}", self.line)?;
+        if let Some(column) = &self.column {
+            write!(f, ", column {}", column)?;
+        }
+        write!(f, ": {} ({})", self.detail, self.reason)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Write every rejection out in the given format
+pub fn write_rejections_to_file(
+    rejections: &[Rejection],
+    format: Format,
+    writer: impl Write,
+) -> Result<(), report::Error> {
+    write_records(rejections, format, writer)
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+
+    use crate::{
+        csv::TransactionReader,
+        ledger::Ledger,
+        reject::{Reason, Rejection, write_rejections_to_file},
+        report::Format,
+        string::StringReader,
+    };
+
+    #[test]
+    fn ledger_rejections_carry_their_row() -> Result<()> {
+        let input = "type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,10\ndeposit,1,1,5\n";
+
+        let mut ledger = Ledger::new();
+        let mut rejections = Vec::new();
+        for row in TransactionReader::new(StringReader::from(input))? {
+            let row = row.map_err(|r| anyhow::anyhow!("Unexpected rejection {:?}", r))?;
+            if let Err(e) = ledger.process_transaction(row.transaction.clone()) {
+                rejections.push(Rejection::from_ledger(row, &e));
+            }
+        }
+
+        assert_eq!(2, rejections.len());
+        assert_eq!(3, rejections[0].line);
+        assert_eq!(Reason::InsufficientFunds, rejections[0].reason);
+        assert_eq!(4, rejections[1].line);
+        assert_eq!(Some(1), rejections[1].tx);
+        assert_eq!(Reason::DuplicateTransaction, rejections[1].reason);
+
+        let mut out = Vec::new();
+        write_rejections_to_file(&rejections, Format::Csv, &mut out)?;
+        let out = String::from_utf8(out)?;
+        let mut lines = out.lines();
+
+        assert_eq!(
+            Some("line,record,tx,reason,detail,source,column"),
+            lines.next()
+        );
+        assert_eq!(
+            Some("3,\"withdrawal,1,2,10\",2,InsufficientFunds,Insufficient funds,,"),
+            lines.next()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! Output formats shared by every report. Each report is a list of flat records which
+//! are written as CSV, a JSON array, newline-delimited JSON or an aligned table.
+
+use std::io::{self, Write};
+
+use csv::{ReaderBuilder, WriterBuilder};
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
+pub enum Error {
+    #[error("I/O Error: {0}")]
+    IOError(#[from] io::Error),
+
+    #[error("CSV Serialization Error: {0}")]
+    CSVError(#[from] csv::Error),
+
+    #[error("JSON Serialization Error: {0}")]
+    JSONError(#[from] serde_json::Error),
+}
+
+/// How the records of a report are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// CSV with a header row
+    #[default]
+    Csv,
+    /// A single JSON array of objects
+    Json,
+    /// One JSON object per line
+    Ndjson,
+    /// Columns padded to line up, for reading in a terminal
+    Table,
+}
+
+/// Write every record in the given format
+pub fn write_records<T, W>(
+    records: impl IntoIterator<Item = T>,
+    format: Format,
+    mut writer: W,
+) -> Result<(), Error>
+where
+    T: Serialize,
+    W: Write,
+{
+    match format {
+        Format::Csv => {
+            let mut csv_writer = WriterBuilder::new().from_writer(&mut writer);
+            for record in records {
+                csv_writer.serialize(record)?;
+            }
+            csv_writer.flush()?;
+        }
+        Format::Json => {
+            let records: Vec<T> = records.into_iter().collect();
+            serde_json::to_writer_pretty(&mut writer, &records)?;
+            writeln!(writer)?;
+        }
+        Format::Ndjson => {
+            for record in records {
+                serde_json::to_writer(&mut writer, &record)?;
+                writeln!(writer)?;
+            }
+        }
+        Format::Table => write_table(records, &mut writer)?,
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Fields are laid out the same way as CSV, then padded to the widest value in each column
+fn write_table<T: Serialize>(
+    records: impl IntoIterator<Item = T>,
+    writer: &mut impl Write,
+) -> Result<(), Error> {
+    let mut csv_writer = WriterBuilder::new().from_writer(Vec::new());
+    for record in records {
+        csv_writer.serialize(record)?;
+    }
+    let csv = csv_writer
+        .into_inner()
+        .map_err(|e| Error::IOError(e.into_error()))?;
+
+    let rows = ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(csv.as_slice())
+        .into_records()
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut widths = Vec::new();
+    for row in &rows {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+
+    for row in &rows {
+        let mut line = String::new();
+        for (idx, (field, width)) in row.iter().zip(&widths).enumerate() {
+            if idx > 0 {
+                line.push_str("  ");
+            }
+            line.push_str(&format!("{:<width$}", field, width = width));
+        }
+        writeln!(writer, "{}", line.trim_end())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Result;
+    use serde::Serialize;
+
+    use crate::report::{Format, write_records};
+
+    #[derive(Serialize)]
+    struct Record {
+        client: u16,
+        name: &'static str,
+        note: Option<&'static str>,
+    }
+
+    fn write(format: Format) -> Result<String> {
+        let records = [
+            Record {
+                client: 1,
+                name: "first",
+                note: None,
+            },
+            Record {
+                client: 200,
+                name: "second",
+                note: Some("late"),
+            },
+        ];
+        let mut out = Vec::new();
+        write_records(records, format, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn write_each_format() -> Result<()> {
+        assert_eq!(
+            "client,name,note\n1,first,\n200,second,late\n",
+            write(Format::Csv)?
+        );
+        assert_eq!(
+            "{\"client\":1,\"name\":\"first\",\"note\":null}\n{\"client\":200,\"name\":\"second\",\"note\":\"late\"}\n",
+            write(Format::Ndjson)?
+        );
+        assert_eq!(
+            "client  name    note\n1       first\n200     second  late\n",
+            write(Format::Table)?
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&write(Format::Json)?)?;
+        assert_eq!(2, json.as_array().map_or(0, Vec::len));
+        assert_eq!("second", json[1]["name"]);
+
+        Ok(())
+    }
+}
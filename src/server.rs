@@ -0,0 +1,418 @@
+//! Long running TCP server which applies transactions to a shared [`Ledger`] as they arrive.
+//!
+//! Every connection speaks a line based protocol. Each line is either a bare CSV transaction
+//! in the same column order as the input files
+//! (`type,client,tx,amount[,currency,operator,reason,timestamp]`) or a
+//! query for a client's balance. Every line gets exactly one reply:
+//!
+//! ```text
+//! > deposit,1,1,10.0
+//! < OK 1
+//! > withdrawal,1,2,50.0
+//! < REJECTED 2 InsufficientFunds
+//! > QUERY 1
+//! < BALANCE 1,XXX,10.0000,0.0000,10.0000,false
+//! < END
+//! ```
+//!
+//! Each connection is served on a thread of its own, so only so many are served at once and
+//! one which sits idle for too long is closed. A connection over the limit is told so and
+//! closed straight away:
+//!
+//! ```text
+//! < ERROR - too many connections
+//! ```
+
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    csv::parse_transaction,
+    journal::Journal,
+    ledger::{Client, Ledger, Transaction},
+    reject::Reason,
+};
+
+/// State shared between every connection. The journal sits under the same lock as the
+/// ledger so records are journaled in exactly the order they're applied.
+struct Shared {
+    ledger: Ledger,
+    journal: Option<Journal>,
+
+    /// Set once the journal fails. Nothing more is accepted as it couldn't be made durable
+    failed: bool,
+}
+
+pub struct Server {
+    listener: TcpListener,
+    shared: Arc<Mutex<Shared>>,
+
+    /// Administrative transactions are rejected unless explicitly allowed
+    allow_admin: bool,
+
+    /// Connections served at once. Each has a thread of its own
+    max_connections: usize,
+
+    /// Connections being served right now
+    connections: Arc<AtomicUsize>,
+
+    /// How long a connection may go without sending a line, or without taking its replies
+    idle_timeout: Option<Duration>,
+}
+
+/// Connections served at once unless [`Server::max_connections`] says otherwise
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// How long a connection may sit idle unless [`Server::idle_timeout`] says otherwise
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Frees a connection's place once it's closed, however its thread ends
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    /// Listen on `addr`, applying transactions to `ledger`. When a journal is given every
+    /// transaction is journaled before it's applied
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        ledger: Ledger,
+        journal: Option<Journal>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+
+        Ok(Server {
+            listener,
+            shared: Arc::new(Mutex::new(Shared {
+                ledger,
+                journal,
+                failed: false,
+            })),
+            allow_admin: false,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            connections: Arc::new(AtomicUsize::new(0)),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        })
+    }
+
+    /// Accept administrative transactions such as unlocks and manual adjustments
+    pub fn allow_admin(mut self, allow: bool) -> Self {
+        self.allow_admin = allow;
+        self
+    }
+
+    /// Serve at most `max` connections at once. Any more are refused until one closes
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max.max(1);
+        self
+    }
+
+    /// Close a connection which sends nothing, or takes none of its replies, for `timeout`.
+    /// Connections are never closed for being idle when `None`
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout.filter(|t| !t.is_zero());
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept connections forever, serving each on its own thread
+    pub fn run(self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let mut stream = stream?;
+
+            if self.connections.fetch_add(1, Ordering::SeqCst) >= self.max_connections {
+                self.connections.fetch_sub(1, Ordering::SeqCst);
+                let _ = writeln!(stream, "ERROR - too many connections");
+                continue;
+            }
+            let slot = ConnectionSlot(self.connections.clone());
+
+            let shared = self.shared.clone();
+            let allow_admin = self.allow_admin;
+            let idle_timeout = self.idle_timeout;
+            thread::spawn(move || {
+                // A connection dropping part way through only affects that connection
+                let _ = serve_connection(&stream, shared, allow_admin, idle_timeout);
+
+                // The place is given up before the connection is seen to close
+                drop(slot);
+            });
+        }
+
+        Ok(())
+    }
+}
+
+fn serve_connection(
+    stream: &TcpStream,
+    shared: Arc<Mutex<Shared>>,
+    allow_admin: bool,
+    idle_timeout: Option<Duration>,
+) -> io::Result<()> {
+    // Replies are small and clients wait on each one
+    stream.set_nodelay(true)?;
+
+    // A read or write which times out ends the connection
+    stream.set_read_timeout(idle_timeout)?;
+    stream.set_write_timeout(idle_timeout)?;
+
+    let reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(stream);
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        respond(line, &shared, allow_admin, &mut writer)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn respond(
+    line: &str,
+    shared: &Mutex<Shared>,
+    allow_admin: bool,
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    if let Some(client) = line.strip_prefix("QUERY") {
+        return match client.trim().parse::<Client>() {
+            Ok(client) => {
+                let snapshots = shared
+                    .lock()
+                    .expect("Lock holders don't panic")
+                    .ledger
+                    .get_client_snapshot(client);
+                for s in snapshots {
+                    writeln!(
+                        writer,
+                        "BALANCE {},{},{},{},{},{}",
+                        s.client, s.currency, s.available, s.held, s.total, s.locked
+                    )?;
+                }
+                writeln!(writer, "END")
+            }
+            Err(_) => writeln!(writer, "REJECTED - {}", Reason::Malformed),
+        };
+    }
+
+    let (t, at) = match parse_transaction(line) {
+        Ok(parsed) => parsed,
+        Err(e) => return writeln!(writer, "REJECTED - {}", Reason::from(&e)),
+    };
+
+    if matches!(t, Transaction::Admin { .. }) && !allow_admin {
+        return writeln!(writer, "REJECTED {} {}", t.tx(), Reason::AdminNotAllowed);
+    }
+
+    let mut shared = shared.lock().expect("Lock holders don't panic");
+    if shared.failed {
+        return writeln!(writer, "ERROR {} journal unavailable", t.tx());
+    }
+
+    // The transaction is journaled before it's applied so nothing is ever applied which
+    // couldn't be made durable. If the ledger goes on to refuse it, replaying the journal
+    // refuses it again at the same point
+    if let Some(journal) = shared.journal.as_mut()
+        && let Err(e) = journal.append(&t, at, None)
+    {
+        shared.failed = true;
+        eprintln!("Journal failed, refusing further transactions: {}", e);
+        return writeln!(writer, "ERROR {} journal unavailable", t.tx());
+    }
+
+    if let Err(e) = shared.ledger.process_transaction_at(t.clone(), at) {
+        return writeln!(writer, "REJECTED {} {}", t.tx(), Reason::from(&e));
+    }
+
+    writeln!(writer, "OK {}", t.tx())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{SocketAddr, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    use anyhow::Result;
+
+    use crate::{
+        journal::{FsyncPolicy, Journal},
+        ledger::Ledger,
+        server::Server,
+    };
+
+    fn start() -> Result<SocketAddr> {
+        let server = Server::bind("127.0.0.1:0", Ledger::new(), None)?;
+        let addr = server.local_addr()?;
+        thread::spawn(move || server.run());
+        Ok(addr)
+    }
+
+    /// Send a line and collect the reply, reading until `END` for queries
+    fn send(
+        stream: &mut TcpStream,
+        reader: &mut BufReader<TcpStream>,
+        line: &str,
+    ) -> Result<Vec<String>> {
+        writeln!(stream, "{}", line)?;
+
+        let mut replies = Vec::new();
+        loop {
+            let mut reply = String::new();
+            reader.read_line(&mut reply)?;
+            let reply = reply.trim_end().to_string();
+            let done = !line.starts_with("QUERY") || reply == "END";
+            replies.push(reply);
+            if done {
+                return Ok(replies);
+            }
+        }
+    }
+
+    #[test]
+    fn accept_reject_and_query() -> Result<()> {
+        let addr = start()?;
+        let mut stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        assert_eq!(
+            vec!["OK 1"],
+            send(&mut stream, &mut reader, "deposit,1,1,10.0")?
+        );
+        assert_eq!(
+            vec!["REJECTED 2 InsufficientFunds"],
+            send(&mut stream, &mut reader, "withdrawal,1,2,50")?
+        );
+        assert_eq!(
+            vec!["REJECTED - UnknownTransactionType"],
+            send(&mut stream, &mut reader, "refund,1,3,1")?
+        );
+        assert_eq!(
+            vec!["BALANCE 1,XXX,10.0000,0.0000,10.0000,false", "END"],
+            send(&mut stream, &mut reader, "QUERY 1")?
+        );
+        assert_eq!(vec!["END"], send(&mut stream, &mut reader, "QUERY 2")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_connections_share_the_ledger() -> Result<()> {
+        let addr = start()?;
+
+        let handles: Vec<_> = (0..8u32)
+            .map(|i| {
+                thread::spawn(move || -> Result<()> {
+                    let mut stream = TcpStream::connect(addr)?;
+                    stream.set_nodelay(true)?;
+                    let mut reader = BufReader::new(stream.try_clone()?);
+                    for j in 0..25 {
+                        let tx = i * 100 + j;
+                        let reply = send(&mut stream, &mut reader, &format!("deposit,1,{},1", tx))?;
+                        assert_eq!(vec![format!("OK {}", tx)], reply);
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().expect("Client should not panic")?;
+        }
+
+        let mut stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        assert_eq!(
+            vec!["BALANCE 1,XXX,200.0000,0.0000,200.0000,false", "END"],
+            send(&mut stream, &mut reader, "QUERY 1")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn connections_are_limited() -> Result<()> {
+        let server = Server::bind("127.0.0.1:0", Ledger::new(), None)?
+            .max_connections(1)
+            .idle_timeout(Some(Duration::from_millis(200)));
+        let addr = server.local_addr()?;
+        thread::spawn(move || server.run());
+
+        let mut first = TcpStream::connect(addr)?;
+        let mut first_reader = BufReader::new(first.try_clone()?);
+        assert_eq!(
+            vec!["OK 1"],
+            send(&mut first, &mut first_reader, "deposit,1,1,10")?
+        );
+
+        // Refused while the first connection holds the only place
+        let mut refused = String::new();
+        BufReader::new(TcpStream::connect(addr)?).read_line(&mut refused)?;
+        assert_eq!("ERROR - too many connections", refused.trim_end());
+
+        // The first connection is closed once it sits idle, which frees its place
+        let mut rest = Vec::new();
+        assert_eq!(0, first_reader.read_to_end(&mut rest)?);
+
+        let mut stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        assert_eq!(
+            vec!["BALANCE 1,XXX,10.0000,0.0000,10.0000,false", "END"],
+            send(&mut stream, &mut reader, "QUERY 1")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn journal_replays_to_the_served_state() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("transactor-server-journal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut ledger = Ledger::new();
+        let (journal, _) = Journal::open(&path, FsyncPolicy::Always, &mut ledger)?;
+        let server = Server::bind("127.0.0.1:0", ledger, Some(journal))?;
+        let addr = server.local_addr()?;
+        thread::spawn(move || server.run());
+
+        let mut stream = TcpStream::connect(addr)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        send(&mut stream, &mut reader, "deposit,1,1,10")?;
+        send(&mut stream, &mut reader, "withdrawal,1,2,50")?;
+        send(&mut stream, &mut reader, "withdrawal,1,3,4")?;
+
+        // The refused withdrawal was journaled ahead of being refused, and is refused again
+        let mut replayed = Ledger::new();
+        let (_, replay) = Journal::open(&path, FsyncPolicy::Always, &mut replayed)?;
+        assert_eq!((2, 1), (replay.applied, replay.skipped));
+        assert_eq!(Some("6".parse()?), replayed.get_available_balance(1));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}